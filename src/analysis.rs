@@ -0,0 +1,245 @@
+use crate::{flow::*, geometry::*, light::*, map::*};
+use cgmath::prelude::InnerSpace;
+use std::f32::consts::*;
+use std::io::{Result, Write};
+
+/// Derived maps of a terrain, mostly used as textures
+impl Map {
+    /// Steepness of the terrain in degrees, `0` being flat and `90` vertical
+    pub fn slope(&self) -> Map {
+        let mut slope = Map::new(self.width(), self.height());
+        slope.map_coords(|x, y, _| {
+            let (dzdx, dzdy) = self.gradient(x, y);
+            dzdx.hypot(dzdy).atan().to_degrees()
+        });
+        slope
+    }
+
+    /// Direction the terrain faces (downhill) in degrees within `0..360`, measured from the +x
+    /// towards the +y axis. Flat cells face `0`.
+    pub fn aspect(&self) -> Map {
+        let mut aspect = Map::new(self.width(), self.height());
+        aspect.map_coords(|x, y, _| {
+            let (dzdx, dzdy) = self.gradient(x, y);
+            if dzdx == 0.0 && dzdy == 0.0 {
+                // the angle of a zero vector depends on the signs of its zeroes
+                return 0.0;
+            }
+            (-dzdy).atan2(-dzdx).to_degrees().rem_euclid(360.0)
+        });
+        aspect
+    }
+
+    /// Curvature of the terrain, positive on convex spots like ridges and peaks and negative on
+    /// concave spots like valleys and pits.
+    ///
+    /// It is the difference between a cell and the average of its 8 neighbors.
+    pub fn curvature(&self) -> Map {
+        let mut curvature = Map::new(self.width(), self.height());
+        curvature.map_coords(|x, y, _| {
+            let (ix, iy) = (x as isize, y as isize);
+            let mut sum = 0.0;
+            for dx in -1..=1 {
+                for dy in -1..=1 {
+                    if (dx, dy) != (0, 0) {
+                        sum += self.get_clamped(ix + dx, iy + dy);
+                    }
+                }
+            }
+            self[(x, y)] - sum / 8.0
+        });
+        curvature
+    }
+
+    /// Horizon based ambient occlusion.
+    ///
+    /// The horizon is searched in `directions` evenly spread directions up to `radius` cells
+    /// away. The result is how much of the sky is visible, `1` on a flat plane and `0` at the
+    /// bottom of an infinitely deep hole.
+    pub fn ambient_occlusion(&self, directions: usize, radius: f32) -> Map {
+        let directions: Vec<_> = (0..directions)
+            .map(|i| (2.0 * PI * i as f32 / directions as f32).sin_cos())
+            .map(|(dy, dx)| (dx, dy))
+            .collect();
+
+        let mut ao = Map::new(self.width(), self.height());
+        ao.map_coords(|x, y, _| {
+            let occlusion: f32 = directions
+                .iter()
+                .map(|&dir| {
+                    let horizon = horizon_tangent(self, x, y, dir, radius, f32::MAX);
+                    horizon.max(0.0).atan().sin()
+                })
+                .sum();
+            1.0 - occlusion / directions.len() as f32
+        });
+        ao
+    }
+
+    /// Approximate distance of each cell to the closest cell whose value satisfies `target`.
+    ///
+    /// Computed with a two pass chamfer transform, where orthogonal steps cost `1` and diagonal
+    /// steps `sqrt(2)`. Cells are `f32::MAX` away if no cell is a target.
+    pub fn distance_to(&self, target: impl Send + Sync + Fn(f32) -> bool) -> Map {
+        let (width, height) = (self.width(), self.height());
+        let mut dist = self.clone();
+        dist.map(|h| if target(h) { 0.0 } else { f32::MAX });
+
+        let relax = |dist: &mut Map, x: usize, y: usize, dx: isize, dy: isize, cost: f32| {
+            let (nx, ny) = (x as isize + dx, y as isize + dy);
+            if nx < 0 || ny < 0 || nx >= width as isize || ny >= height as isize {
+                return;
+            }
+            let d = dist[(nx as usize, ny as usize)] + cost;
+            if d < dist[(x, y)] {
+                dist[(x, y)] = d;
+            }
+        };
+
+        // forward pass from the upper left, backward pass from the lower right corner
+        for y in 0..height {
+            for x in 0..width {
+                relax(&mut dist, x, y, -1, 0, 1.0);
+                relax(&mut dist, x, y, 0, -1, 1.0);
+                relax(&mut dist, x, y, -1, -1, SQRT_2);
+                relax(&mut dist, x, y, 1, -1, SQRT_2);
+            }
+        }
+        for y in (0..height).rev() {
+            for x in (0..width).rev() {
+                relax(&mut dist, x, y, 1, 0, 1.0);
+                relax(&mut dist, x, y, 0, 1, 1.0);
+                relax(&mut dist, x, y, 1, 1, SQRT_2);
+                relax(&mut dist, x, y, -1, 1, SQRT_2);
+            }
+        }
+
+        dist
+    }
+
+    /// Exports the direction water flows to from each cell as RGB png.
+    ///
+    /// The vector from a cell to its flow target is encoded like a normal map, each component
+    /// being mapped from `-1..=1` to `0..=255`. Cells without a lower target get `(0, 0, 1)`.
+    #[allow(unused)]
+    pub fn export_flow_direction<W: Write>(&self, targets: &FlowField, writer: W) -> Result<()> {
+        write_png_rows(
+            writer,
+            self.width(),
+            self.height(),
+            png::ColorType::RGB,
+            |y, row| {
+                for (x, rgb) in row.chunks_mut(3).enumerate() {
+                    let (tx, ty) = targets.target(x, y);
+                    let dir = [
+                        tx as f32 - x as f32,
+                        ty as f32 - y as f32,
+                        self[(tx, ty)] - self[(x, y)],
+                    ];
+                    let len = dir.iter().map(|d| d * d).sum::<f32>().sqrt();
+                    let dir = if len > 0.0 {
+                        dir.map(|d| d / len)
+                    } else {
+                        [0.0, 0.0, 1.0]
+                    };
+                    encode_direction(dir, rgb);
+                }
+            },
+        )
+    }
+
+    /// Exports a tangent space normal map as RGB png.
+    ///
+    /// `height_scale` is the height of one unit of the map relative to the width of a cell, so
+    /// larger values give more pronounced normals.
+    #[allow(unused)]
+    pub fn export_normal_map<W: Write>(
+        &self,
+        writer: W,
+        height_scale: f32,
+        convention: NormalConvention,
+    ) -> Result<()> {
+        write_png_rows(
+            writer,
+            self.width(),
+            self.height(),
+            png::ColorType::RGB,
+            |y, row| self.normal_map_row(y, row, height_scale, convention),
+        )
+    }
+
+    /// Writes the normals of row `y` into an RGB buffer
+    fn normal_map_row(
+        &self,
+        y: usize,
+        row: &mut [u8],
+        height_scale: f32,
+        convention: NormalConvention,
+    ) {
+        for (x, rgb) in row.chunks_mut(3).enumerate() {
+            let (dzdx, dzdy) = self.gradient(x, y);
+            let normal = Vector::new(-dzdx * height_scale, -dzdy * height_scale, 1.0).normalize();
+
+            // the map's y axis points down in the image
+            let green = match convention {
+                NormalConvention::OpenGl => -normal.y,
+                NormalConvention::DirectX => normal.y,
+            };
+            encode_direction([normal.x, green, normal.z], rgb);
+        }
+    }
+}
+
+/// Direction of the green channel of a normal map
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum NormalConvention {
+    /// green points up in the image (Y+), as used by OpenGL, Blender and Unity
+    OpenGl,
+    /// green points down in the image (Y-), as used by DirectX and Unreal
+    #[allow(unused)]
+    DirectX,
+}
+
+/// Maps the components of a unit vector from `-1..=1` to `0..=255`
+fn encode_direction(dir: [f32; 3], rgb: &mut [u8]) {
+    for (b, d) in rgb.iter_mut().zip(dir.iter()) {
+        *b = (127.5 * (d + 1.0)).round() as u8;
+    }
+}
+
+/// Check the derived maps on a plane rising towards +x with 45 degrees
+#[test]
+fn test_plane_analysis() {
+    let mut map = Map::new(32, 32);
+    map.map_coords(|x, _, _| x as f32);
+
+    let (x, y) = (16, 16);
+    assert!((map.slope()[(x, y)] - 45.0).abs() < 1e-4);
+    assert!((map.aspect()[(x, y)] - 180.0).abs() < 1e-4);
+    assert!(map.curvature()[(x, y)].abs() < 1e-4);
+
+    let mut flat = Map::new(32, 32);
+    flat.map(|_| 5.0);
+    assert_eq!(flat.aspect()[(x, y)], 0.0);
+    assert_eq!(flat.ambient_occlusion(8, 8.0)[(x, y)], 1.0);
+    // half of the directions look uphill, so some of the sky is covered
+    assert!(map.ambient_occlusion(8, 8.0)[(x, y)] < 0.8);
+}
+
+/// Check that the green channel follows the chosen convention on a slope facing up in the image
+#[test]
+fn test_normal_map_convention() {
+    let mut map = Map::new(8, 8);
+    map.map_coords(|_, y, _| y as f32);
+
+    let mut gl = [0u8; 3 * 8];
+    let mut dx = [0u8; 3 * 8];
+    map.normal_map_row(4, &mut gl, 1.0, NormalConvention::OpenGl);
+    map.normal_map_row(4, &mut dx, 1.0, NormalConvention::DirectX);
+
+    let idx = 3 * 4;
+    assert_eq!(gl[idx], 128);
+    assert!(gl[idx + 1] > 200);
+    assert!(dx[idx + 1] < 55);
+    assert_eq!(gl[idx + 2], dx[idx + 2]);
+}
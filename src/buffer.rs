@@ -0,0 +1,240 @@
+use std::io::Result;
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicU32;
+use std::sync::Mutex;
+
+/// Where large buffers are stored instead of the heap, set by [`spill_to_disk`](./fn.spill_to_disk.html)
+static SPILL: Mutex<Option<Spill>> = Mutex::new(None);
+
+struct Spill {
+    dir: PathBuf,
+    min_bytes: usize,
+}
+
+/// Stores every buffer of at least `min_bytes` that is created from now on in a memory-mapped
+/// file in `dir`.
+///
+/// The operating system can write the pages of these files back to disk and evict them when
+/// memory runs short, which allows generating maps that don't fit into memory at once.
+pub fn spill_to_disk(dir: impl Into<PathBuf>, min_bytes: usize) {
+    *SPILL.lock().unwrap() = Some(Spill {
+        dir: dir.into(),
+        min_bytes,
+    });
+}
+
+/// Data for which all bytes being zero is a valid value
+///
+/// # Safety
+/// Implementors must not have any invalid bit patterns, padding or drop glue.
+pub unsafe trait Zeroable: Send + Sync + 'static {}
+
+unsafe impl Zeroable for bool {}
+unsafe impl Zeroable for u8 {}
+unsafe impl Zeroable for u32 {}
+unsafe impl Zeroable for i32 {}
+unsafe impl Zeroable for usize {}
+unsafe impl Zeroable for f32 {}
+unsafe impl Zeroable for AtomicU32 {}
+
+/// Fixed size array of cells, which lives either on the heap or in a memory-mapped file
+pub struct Buffer<T: Zeroable> {
+    storage: Storage<T>,
+}
+
+enum Storage<T> {
+    Heap(Vec<T>),
+    #[cfg(unix)]
+    Mapped {
+        ptr: *mut T,
+        len: usize,
+    },
+}
+
+// the mapping is owned by the buffer just like the memory of a `Vec`
+unsafe impl<T: Zeroable> Send for Buffer<T> {}
+unsafe impl<T: Zeroable> Sync for Buffer<T> {}
+
+impl<T: Zeroable> Buffer<T> {
+    /// Creates a buffer of `len` zeroed cells, which is memory-mapped if it is large enough and
+    /// [`spill_to_disk`](./fn.spill_to_disk.html) has been called.
+    ///
+    /// If the buffer can't be mapped, for example because the disk is full, a warning is printed
+    /// and this and all later buffers are kept on the heap.
+    pub fn zeroed(len: usize) -> Self {
+        let bytes = len * std::mem::size_of::<T>();
+        let mut spill = SPILL.lock().unwrap();
+        if let Some(Spill { dir, min_bytes }) = spill.as_ref() {
+            if bytes > 0 && bytes >= *min_bytes {
+                match Self::mapped(len, dir) {
+                    Ok(buffer) => return buffer,
+                    Err(err) => {
+                        eprintln!(
+                            "Can't map buffer in {:?}, keeping buffers on the heap: {}",
+                            dir, err
+                        );
+                        *spill = None;
+                    }
+                }
+            }
+        }
+        Self {
+            storage: Storage::Heap(zeroed_vec(len)),
+        }
+    }
+
+    /// Creates a buffer of `len` zeroed cells in a memory-mapped temporary file in `dir`
+    #[cfg(unix)]
+    pub fn mapped(len: usize, dir: &Path) -> Result<Self> {
+        use std::os::unix::io::AsRawFd;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+        let bytes = len * std::mem::size_of::<T>();
+        let name = format!(
+            "islands-{}-{}.buf",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = dir.join(name);
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        // the file is only reachable through the mapping from now on, so it is cleaned up even if
+        // the process doesn't exit normally
+        std::fs::remove_file(&path)?;
+        // files are extended with zeroes
+        file.set_len(bytes as u64)?;
+
+        if bytes == 0 {
+            return Ok(Self {
+                storage: Storage::Heap(Vec::new()),
+            });
+        }
+
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                bytes,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            storage: Storage::Mapped {
+                ptr: ptr as *mut T,
+                len,
+            },
+        })
+    }
+
+    /// Memory mapping isn't supported on this platform, so the buffer is kept on the heap
+    #[cfg(not(unix))]
+    pub fn mapped(len: usize, _dir: &Path) -> Result<Self> {
+        Ok(Self {
+            storage: Storage::Heap(zeroed_vec(len)),
+        })
+    }
+
+    /// Whether the buffer is stored in a memory-mapped file
+    #[allow(unused)]
+    pub fn is_mapped(&self) -> bool {
+        !matches!(self.storage, Storage::Heap(_))
+    }
+}
+
+/// Allocates a vector of zeroed cells, which lets the allocator hand out fresh zeroed pages
+/// instead of writing the zeroes
+fn zeroed_vec<T: Zeroable>(len: usize) -> Vec<T> {
+    let layout = std::alloc::Layout::array::<T>(len).expect("buffer too large");
+    if layout.size() == 0 {
+        return Vec::new();
+    }
+    unsafe {
+        let ptr = std::alloc::alloc_zeroed(layout);
+        if ptr.is_null() {
+            std::alloc::handle_alloc_error(layout);
+        }
+        // all zeroes are a valid value of every cell
+        Vec::from_raw_parts(ptr as *mut T, len, len)
+    }
+}
+
+impl<T: Zeroable> Deref for Buffer<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        match &self.storage {
+            Storage::Heap(vec) => vec,
+            #[cfg(unix)]
+            Storage::Mapped { ptr, len } => unsafe { std::slice::from_raw_parts(*ptr, *len) },
+        }
+    }
+}
+
+impl<T: Zeroable> DerefMut for Buffer<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        match &mut self.storage {
+            Storage::Heap(vec) => vec,
+            #[cfg(unix)]
+            Storage::Mapped { ptr, len } => unsafe { std::slice::from_raw_parts_mut(*ptr, *len) },
+        }
+    }
+}
+
+impl<T: Zeroable + Copy> Clone for Buffer<T> {
+    fn clone(&self) -> Self {
+        let mut clone = Self::zeroed(self.len());
+        clone.copy_from_slice(self);
+        clone
+    }
+}
+
+impl<T: Zeroable> Drop for Buffer<T> {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Storage::Mapped { ptr, len } = self.storage {
+            unsafe {
+                libc::munmap(ptr as *mut _, len * std::mem::size_of::<T>());
+            }
+        }
+    }
+}
+
+/// Check that memory-mapped buffers start zeroed and keep their values when cloned
+#[test]
+fn test_mapped_buffer() {
+    let mut buffer = Buffer::<f32>::mapped(100_000, &std::env::temp_dir()).unwrap();
+    #[cfg(unix)]
+    assert!(buffer.is_mapped());
+    assert!(buffer.iter().all(|&v| v == 0.0));
+
+    for (i, v) in buffer.iter_mut().enumerate() {
+        *v = i as f32;
+    }
+    let clone = buffer.clone();
+    assert_eq!(&buffer[..], &clone[..]);
+    assert_eq!(clone[99_999], 99_999.0);
+}
+
+/// Check that buffers which can't be mapped are kept on the heap instead
+#[test]
+fn test_spill_failure() {
+    // only buffers this large are spilled, which no other test creates
+    let len = 12_345_679;
+    spill_to_disk("missing-spill-directory", len * 4);
+    let buffer = Buffer::<f32>::zeroed(len);
+    assert!(!buffer.is_mapped());
+    assert!(buffer.iter().all(|&v| v == 0.0));
+    assert!(SPILL.lock().unwrap().is_none());
+}
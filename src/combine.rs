@@ -0,0 +1,367 @@
+use crate::map::*;
+use rayon::prelude::*;
+use std::ops::*;
+
+/// Arithmetic and combinators of whole maps, applied to all cells in parallel.
+///
+/// Maps which are combined with each other must have the same size.
+impl Map {
+    /// Combines each cell with the cell of `other` at the same position
+    pub fn zip_with<F>(&mut self, other: &Map, fun: F)
+    where
+        F: Send + Sync + Fn(f32, f32) -> f32,
+    {
+        assert_same_size(self, other);
+        self.values_mut()
+            .par_iter_mut()
+            .zip(other.values().par_iter())
+            .for_each(|(h, &o)| *h = fun(*h, o));
+    }
+
+    /// New map of the cells of this map and `other` combined with `fun`
+    pub fn zip_map<F>(&self, other: &Map, fun: F) -> Map
+    where
+        F: Send + Sync + Fn(f32, f32) -> f32,
+    {
+        let mut map = self.clone();
+        map.zip_with(other, fun);
+        map
+    }
+
+    /// New map of the cells of this map, `a` and `b` combined with `fun`
+    pub fn zip3_map<F>(&self, a: &Map, b: &Map, fun: F) -> Map
+    where
+        F: Send + Sync + Fn(f32, f32, f32) -> f32,
+    {
+        assert_same_size(self, a);
+        assert_same_size(self, b);
+        let mut map = self.clone();
+        map.values_mut()
+            .par_iter_mut()
+            .zip(a.values().par_iter())
+            .zip(b.values().par_iter())
+            .for_each(|((h, &a), &b)| *h = fun(*h, a, b));
+        map
+    }
+
+    /// Lower value of the two maps at each cell
+    #[allow(unused)]
+    pub fn min(&self, other: &Map) -> Map {
+        self.zip_map(other, f32::min)
+    }
+
+    /// Higher value of the two maps at each cell
+    pub fn max(&self, other: &Map) -> Map {
+        self.zip_map(other, f32::max)
+    }
+
+    /// Values limited to be at least `min` and at most `max` at each cell, `max` wins where they
+    /// overlap
+    #[allow(unused)]
+    pub fn clamp(&self, min: &Map, max: &Map) -> Map {
+        self.zip3_map(min, max, |h, min, max| h.max(min).min(max))
+    }
+
+    /// Blends from this map where `t` is `0` to `other` where `t` is `1`
+    #[allow(unused)]
+    pub fn lerp(&self, other: &Map, t: &Map) -> Map {
+        self.zip3_map(other, t, |a, b, t| a + (b - a) * t)
+    }
+
+    /// Sum of all values, `0` for an empty map
+    #[allow(unused)]
+    pub fn sum(&self) -> f32 {
+        // rows are summed up in parallel and then one after another, so the result doesn't depend
+        // on the number of threads
+        let rows: Vec<f64> = self
+            .values()
+            .par_chunks(self.width().max(1))
+            .map(|row| row.iter().map(|&h| h as f64).sum())
+            .collect();
+        rows.iter().sum::<f64>() as f32
+    }
+
+    /// Average of all values
+    #[allow(unused)]
+    pub fn mean(&self) -> f32 {
+        assert!(!self.values().is_empty(), "mean of an empty map");
+        self.sum() / (self.width() * self.height()) as f32
+    }
+
+    /// Number of values in each of `bins` equally wide bins from `min` to `max`, values outside
+    /// of this range aren't counted
+    #[allow(unused)]
+    pub fn histogram(&self, bins: usize, min: f32, max: f32) -> Vec<usize> {
+        assert!(bins > 0, "histogram without any bins");
+        assert!(
+            max > min,
+            "histogram range from {} to {} is empty",
+            min,
+            max
+        );
+        let scale = bins as f32 / (max - min);
+        self.values()
+            .par_chunks(self.width().max(1))
+            .fold(
+                || vec![0; bins],
+                |mut counts, row| {
+                    for &h in row.iter().filter(|&&h| h >= min && h <= max) {
+                        let bin = (((h - min) * scale) as usize).min(bins - 1);
+                        counts[bin] += 1;
+                    }
+                    counts
+                },
+            )
+            .reduce(
+                || vec![0; bins],
+                |mut a, b| {
+                    for (a, b) in a.iter_mut().zip(b.iter()) {
+                        *a += b;
+                    }
+                    a
+                },
+            )
+    }
+
+    /// Value below which `p` percent of the values lie, interpolated between the two closest
+    /// values
+    #[allow(unused)]
+    pub fn percentile(&self, p: f32) -> f32 {
+        assert!((0.0..=100.0).contains(&p), "percentile {} out of range", p);
+        assert!(!self.values().is_empty(), "percentile of an empty map");
+        let mut values = self.values().to_vec();
+        let rank = p / 100.0 * (values.len() - 1) as f32;
+        let idx = rank as usize;
+
+        let (_, &mut low, above) = values.select_nth_unstable_by(idx, f32::total_cmp);
+        match above.iter().cloned().min_by(f32::total_cmp) {
+            Some(high) => low + (high - low) * (rank - idx as f32),
+            None => low,
+        }
+    }
+
+    /// Mask of the cells for which `fun` returns `true`
+    #[allow(unused)]
+    pub fn mask<F>(&self, fun: F) -> Mask
+    where
+        F: Send + Sync + Fn(f32) -> bool,
+    {
+        let mut mask = Mask::new(self.width(), self.height());
+        mask.values_mut()
+            .par_iter_mut()
+            .zip(self.values().par_iter())
+            .for_each(|(m, &h)| *m = fun(h));
+        mask
+    }
+
+    /// Values of `other` where `mask` is set, and of this map elsewhere
+    #[allow(unused)]
+    pub fn select(&self, mask: &Mask, other: &Map) -> Map {
+        assert_same_size(self, other);
+        assert_eq!((mask.width(), mask.height()), (self.width(), self.height()));
+        let mut map = self.clone();
+        map.values_mut()
+            .par_iter_mut()
+            .zip(other.values().par_iter())
+            .zip(mask.values().par_iter())
+            .for_each(|((h, &o), &m)| {
+                if m {
+                    *h = o;
+                }
+            });
+        map
+    }
+}
+
+fn assert_same_size(a: &Map, b: &Map) {
+    assert_eq!(
+        (a.width(), a.height()),
+        (b.width(), b.height()),
+        "maps of different sizes"
+    );
+}
+
+macro_rules! impl_op {
+    ($op:ident, $fun:ident, $assign:ident, $assign_fun:ident) => {
+        impl $assign<&Map> for Map {
+            fn $assign_fun(&mut self, other: &Map) {
+                self.zip_with(other, |a, b| $op::$fun(a, b));
+            }
+        }
+
+        impl $assign<f32> for Map {
+            fn $assign_fun(&mut self, other: f32) {
+                self.map(|a| $op::$fun(a, other));
+            }
+        }
+
+        impl $op<&Map> for Map {
+            type Output = Map;
+            fn $fun(mut self, other: &Map) -> Map {
+                $assign::$assign_fun(&mut self, other);
+                self
+            }
+        }
+
+        impl $op<f32> for Map {
+            type Output = Map;
+            fn $fun(mut self, other: f32) -> Map {
+                $assign::$assign_fun(&mut self, other);
+                self
+            }
+        }
+
+        impl $op<&Map> for &Map {
+            type Output = Map;
+            fn $fun(self, other: &Map) -> Map {
+                $op::$fun(self.clone(), other)
+            }
+        }
+
+        impl $op<f32> for &Map {
+            type Output = Map;
+            fn $fun(self, other: f32) -> Map {
+                $op::$fun(self.clone(), other)
+            }
+        }
+    };
+}
+
+impl_op!(Add, add, AddAssign, add_assign);
+impl_op!(Sub, sub, SubAssign, sub_assign);
+impl_op!(Mul, mul, MulAssign, mul_assign);
+impl_op!(Div, div, DivAssign, div_assign);
+
+impl Neg for Map {
+    type Output = Map;
+    fn neg(mut self) -> Map {
+        self.map(|h| -h);
+        self
+    }
+}
+
+impl Neg for &Map {
+    type Output = Map;
+    fn neg(self) -> Map {
+        -self.clone()
+    }
+}
+
+/// Grid of booleans, for example of the cells in a certain range of heights
+pub type Mask = Grid<bool>;
+
+#[allow(unused)]
+impl Mask {
+    /// Number of cells which are set
+    pub fn count(&self) -> usize {
+        self.values().par_iter().filter(|&&m| m).count()
+    }
+
+    /// Map which is `1` where the mask is set and `0` elsewhere, to blend maps with
+    /// [`Map::lerp`](../grid/struct.Grid.html#method.lerp)
+    pub fn to_map(&self) -> Map {
+        let mut map = Map::new(self.width(), self.height());
+        map.values_mut()
+            .par_iter_mut()
+            .zip(self.values().par_iter())
+            .for_each(|(h, &m)| *h = if m { 1.0 } else { 0.0 });
+        map
+    }
+
+    /// Combines each cell with the cell of `other` at the same position
+    fn zip_map(&self, other: &Mask, fun: impl Send + Sync + Fn(bool, bool) -> bool) -> Mask {
+        assert_eq!(
+            (self.width(), self.height()),
+            (other.width(), other.height())
+        );
+        let mut mask = self.clone();
+        mask.values_mut()
+            .par_iter_mut()
+            .zip(other.values().par_iter())
+            .for_each(|(a, &b)| *a = fun(*a, b));
+        mask
+    }
+}
+
+impl BitAnd for &Mask {
+    type Output = Mask;
+    fn bitand(self, other: &Mask) -> Mask {
+        self.zip_map(other, |a, b| a && b)
+    }
+}
+
+impl BitOr for &Mask {
+    type Output = Mask;
+    fn bitor(self, other: &Mask) -> Mask {
+        self.zip_map(other, |a, b| a || b)
+    }
+}
+
+impl Not for &Mask {
+    type Output = Mask;
+    fn not(self) -> Mask {
+        let mut mask = self.clone();
+        mask.map(|m| !m);
+        mask
+    }
+}
+
+/// Check the operators and combinators on a few cells
+#[test]
+fn test_map_arithmetic() {
+    let mut a = Map::new(3, 2);
+    a.map_coords(|x, y, _| (x + 3 * y) as f32);
+    let mut b = Map::new(3, 2);
+    b.map(|_| 2.0);
+
+    assert_eq!((&a + &b)[(2, 1)], 7.0);
+    assert_eq!((&a - &b)[(2, 1)], 3.0);
+    assert_eq!((&a * &b)[(2, 1)], 10.0);
+    assert_eq!((&a / &b)[(2, 1)], 2.5);
+    assert_eq!((-(&a * 0.5) + 1.0)[(2, 1)], -1.5);
+
+    let mut c = a.clone();
+    c -= &b;
+    c *= 3.0;
+    assert_eq!(c[(0, 0)], -6.0);
+    assert_eq!(c[(1, 1)], 6.0);
+
+    assert_eq!(a.min(&b)[(1, 0)], 1.0);
+    assert_eq!(a.max(&b)[(1, 0)], 2.0);
+    let zero = Map::new(3, 2);
+    assert_eq!(a.clamp(&zero, &b)[(2, 1)], 2.0);
+    let mut half = Map::new(3, 2);
+    half.map(|_| 0.5);
+    assert_eq!(a.lerp(&b, &half)[(2, 1)], 3.5);
+    assert_eq!(a.zip3_map(&b, &half, |a, b, c| a * b + c)[(1, 1)], 8.5);
+}
+
+/// Check the reductions and masks on a map with the values `0` to `99`, and on an empty map
+#[test]
+fn test_map_reductions() {
+    let mut map = Map::new(10, 10);
+    map.map_coords(|x, y, _| (x + 10 * y) as f32);
+
+    assert_eq!(map.sum(), 4950.0);
+    assert_eq!(map.mean(), 49.5);
+    assert_eq!(map.histogram(4, 0.0, 80.0), vec![20, 20, 20, 21]);
+    assert_eq!(map.percentile(0.0), 0.0);
+    assert_eq!(map.percentile(50.0), 49.5);
+    assert_eq!(map.percentile(100.0), 99.0);
+
+    let low = map.mask(|h| h < 30.0);
+    let even = map.mask(|h| h % 2.0 == 0.0);
+    assert_eq!(low.count(), 30);
+    assert_eq!((&low & &even).count(), 15);
+    assert_eq!((&low | &even).count(), 65);
+    assert_eq!((!&low).count(), 70);
+    assert!(low[(9, 2)] && !low[(0, 3)]);
+
+    let empty = Map::new(0, 3);
+    assert_eq!(empty.sum(), 0.0);
+    assert_eq!(empty.histogram(2, 0.0, 1.0), vec![0, 0]);
+
+    let selected = map.select(&low, &Map::new(10, 10));
+    assert_eq!(selected.sum(), 4950.0 - 435.0);
+    assert_eq!(low.to_map().sum(), 30.0);
+}
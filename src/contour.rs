@@ -0,0 +1,310 @@
+use crate::{geometry::*, map::*};
+use rayon::prelude::*;
+
+/// Options of the contour lines drawn on top of a map
+#[derive(Copy, Clone, Debug)]
+pub struct Contours {
+    /// height difference between two neighboring lines, which must be positive
+    pub interval: f32,
+    /// height of one of the lines, all the others are a multiple of `interval` away from it
+    pub offset: f32,
+    /// every n-th line is drawn as a major line, `0` disables major lines
+    pub major_every: usize,
+    pub color: [u8; 3],
+    /// width of minor lines in pixels
+    pub width: f32,
+    /// width of major lines in pixels
+    pub major_width: f32,
+    pub opacity: f32,
+    pub major_opacity: f32,
+    /// label the major lines with their height
+    pub labels: bool,
+}
+
+impl Default for Contours {
+    fn default() -> Self {
+        Self {
+            interval: 10.0,
+            offset: 5.0,
+            major_every: 5,
+            color: [255; 3],
+            width: 1.0,
+            major_width: 2.0,
+            opacity: 0.2,
+            major_opacity: 0.35,
+            labels: false,
+        }
+    }
+}
+
+/// Line segment of a contour line, between two points in map coordinates
+#[derive(Copy, Clone, Debug)]
+pub struct Segment {
+    pub level: f32,
+    pub from: (f32, f32),
+    pub to: (f32, f32),
+}
+
+/// Minimal distance in pixels between two labels
+const LABEL_SPACING: f32 = 150.0;
+/// Size of a label pixel in image pixels
+const LABEL_SCALE: usize = 2;
+const GLYPH_WIDTH: usize = 3;
+const GLYPH_HEIGHT: usize = 5;
+
+/// Finds the contour line segments of all the `levels` using marching squares.
+///
+/// Every cell of the map is treated as a sample point in its center, so a square consists of
+/// four neighboring cells.
+pub fn contour_segments(map: &Map, levels: &[f32]) -> Vec<Segment> {
+    let (width, height) = (map.width(), map.height());
+    if width < 2 || height < 2 {
+        return Vec::new();
+    }
+
+    (0..height - 1)
+        .into_par_iter()
+        .flat_map_iter(|y| {
+            let mut segments = Vec::new();
+            for x in 0..width - 1 {
+                square_segments(map, x, y, levels, &mut segments);
+            }
+            segments
+        })
+        .collect()
+}
+
+/// Adds the segments of all levels crossing the square with the upper left corner `(x, y)`
+fn square_segments(map: &Map, x: usize, y: usize, levels: &[f32], out: &mut Vec<Segment>) {
+    // corners in clockwise order
+    let corners = [(x, y), (x + 1, y), (x + 1, y + 1), (x, y + 1)];
+    let values = corners.map(|c| map[c]);
+
+    let min = values.iter().cloned().fold(f32::MAX, f32::min);
+    let max = values.iter().cloned().fold(f32::MIN, f32::max);
+
+    for &level in levels.iter() {
+        if level < min || level >= max {
+            continue;
+        }
+
+        // crossing point on the edge starting at corner `i`, if there is one
+        let crossing = |i: usize| {
+            let j = (i + 1) % 4;
+            let (a, b) = (values[i], values[j]);
+            if (a >= level) == (b >= level) {
+                return None;
+            }
+            let t = (level - a) / (b - a);
+            let (ax, ay) = (corners[i].0 as f32, corners[i].1 as f32);
+            let (bx, by) = (corners[j].0 as f32, corners[j].1 as f32);
+            Some((ax + t * (bx - ax), ay + t * (by - ay)))
+        };
+        let edges = [crossing(0), crossing(1), crossing(2), crossing(3)];
+
+        let mut push = |a: usize, b: usize| {
+            if let (Some(from), Some(to)) = (edges[a], edges[b]) {
+                out.push(Segment { level, from, to });
+            }
+        };
+
+        if edges.iter().all(Option::is_some) {
+            // saddle point, resolved with the value in the middle of the square
+            let center = values.iter().sum::<f32>() * 0.25;
+            if (values[0] >= level) == (center >= level) {
+                push(0, 1);
+                push(2, 3);
+            } else {
+                push(3, 0);
+                push(1, 2);
+            }
+        } else {
+            let mut crossed = (0..4).filter(|&i| edges[i].is_some());
+            if let (Some(a), Some(b)) = (crossed.next(), crossed.next()) {
+                push(a, b);
+            }
+        }
+    }
+}
+
+impl Contours {
+    /// All contour levels within the range of heights of the map
+    pub fn levels(&self, map: &Map) -> Vec<f32> {
+        assert!(
+            self.interval > 0.0,
+            "contour interval {} isn't positive",
+            self.interval
+        );
+        let (min, max) = map.minmax();
+        let first = ((min - self.offset) / self.interval).ceil() as i64;
+        let last = ((max - self.offset) / self.interval).floor() as i64;
+        (first..=last)
+            .map(|k| self.offset + k as f32 * self.interval)
+            .collect()
+    }
+
+    fn is_major(&self, level: f32) -> bool {
+        if self.major_every == 0 {
+            return false;
+        }
+        let k = ((level - self.offset) / self.interval).round() as i64;
+        k.rem_euclid(self.major_every as i64) == 0
+    }
+
+    /// Draws anti-aliased contour lines and returns how opaque the line color is on each cell
+    pub fn rasterize(&self, map: &Map) -> Map {
+        let mut alpha = Map::new(map.width(), map.height());
+        let segments = contour_segments(map, &self.levels(map));
+
+        for segment in segments.iter() {
+            let (width, opacity) = if self.is_major(segment.level) {
+                (self.major_width, self.major_opacity)
+            } else {
+                (self.width, self.opacity)
+            };
+            draw_segment(&mut alpha, segment, width, opacity);
+        }
+
+        if self.labels {
+            self.draw_labels(&mut alpha, &segments);
+        }
+
+        alpha
+    }
+
+    /// Writes the height of major lines next to them, keeping some distance between the labels
+    fn draw_labels(&self, alpha: &mut Map, segments: &[Segment]) {
+        let mut placed: Vec<(f32, f32)> = Vec::new();
+
+        for segment in segments.iter().filter(|s| self.is_major(s.level)) {
+            let x = 0.5 * (segment.from.0 + segment.to.0);
+            let y = 0.5 * (segment.from.1 + segment.to.1);
+
+            let too_close = placed
+                .iter()
+                .any(|&(px, py)| (px - x).hypot(py - y) < LABEL_SPACING);
+            if too_close {
+                continue;
+            }
+
+            let text = format!("{}", segment.level.round() as i64);
+            if draw_text(alpha, &text, x as usize, y as usize) {
+                placed.push((x, y));
+            }
+        }
+    }
+}
+
+/// Draws a single segment with a smooth falloff of half a pixel at its edges
+fn draw_segment(alpha: &mut Map, segment: &Segment, width: f32, opacity: f32) {
+    let half = 0.5 * width;
+    let (ax, ay) = segment.from;
+    let (bx, by) = segment.to;
+
+    let reach = half + 1.0;
+    let x0 = (ax.min(bx) - reach).floor().max(0.0) as usize;
+    let y0 = (ay.min(by) - reach).floor().max(0.0) as usize;
+    let x1 = ((ax.max(bx) + reach).ceil() as usize).min(alpha.width() - 1);
+    let y1 = ((ay.max(by) + reach).ceil() as usize).min(alpha.height() - 1);
+
+    for y in y0..=y1 {
+        for x in x0..=x1 {
+            let dist = distance_to_segment((x as f32, y as f32), segment.from, segment.to);
+            let coverage = (half + 0.5 - dist).clamp(0.0, 1.0);
+            let a = &mut alpha[(x, y)];
+            *a = a.max(coverage * opacity);
+        }
+    }
+}
+
+/// Draws `text` centered on `(x, y)` with a cleared background.
+///
+/// Returns `false` without drawing anything if the text doesn't fit into the map.
+fn draw_text(alpha: &mut Map, text: &str, x: usize, y: usize) -> bool {
+    let advance = (GLYPH_WIDTH + 1) * LABEL_SCALE;
+    let text_width = text.len() * advance;
+    let text_height = GLYPH_HEIGHT * LABEL_SCALE;
+
+    // one empty label pixel around the text, so that the line doesn't run through it
+    let margin = LABEL_SCALE;
+    if x < text_width / 2 + margin || y < text_height / 2 + margin {
+        return false;
+    }
+    let (left, top) = (x - text_width / 2, y - text_height / 2);
+    if left + text_width + margin >= alpha.width() || top + text_height + margin >= alpha.height() {
+        return false;
+    }
+
+    for py in top - margin..top + text_height + margin {
+        for px in left - margin..left + text_width + margin {
+            alpha[(px, py)] = 0.0;
+        }
+    }
+
+    for (i, c) in text.chars().enumerate() {
+        let rows = glyph(c);
+        for (gy, row) in rows.iter().enumerate() {
+            for gx in 0..GLYPH_WIDTH {
+                if row & (1 << (GLYPH_WIDTH - 1 - gx)) == 0 {
+                    continue;
+                }
+                for sy in 0..LABEL_SCALE {
+                    for sx in 0..LABEL_SCALE {
+                        let px = left + i * advance + gx * LABEL_SCALE + sx;
+                        let py = top + gy * LABEL_SCALE + sy;
+                        alpha[(px, py)] = 1.0;
+                    }
+                }
+            }
+        }
+    }
+
+    true
+}
+
+/// 3x5 bitmap font, each row is stored in the lowest three bits
+fn glyph(c: char) -> [u8; GLYPH_HEIGHT] {
+    match c {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b010, 0b010, 0b010],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        _ => [0; GLYPH_HEIGHT],
+    }
+}
+
+/// Check that the contour of a cone is a closed ring at the right distance from its top
+#[test]
+fn test_cone_contour() {
+    let size = 64;
+    let mut map = Map::new(size, size);
+    map.map_coords(|x, y, _| 40.0 - (x as f32 - 32.0).hypot(y as f32 - 32.0));
+
+    let segments = contour_segments(&map, &[20.0]);
+    assert!(!segments.is_empty());
+
+    for s in segments.iter() {
+        for &(x, y) in [s.from, s.to].iter() {
+            let r = (x - 32.0).hypot(y - 32.0);
+            assert!((r - 20.0).abs() < 0.1, "point at radius {}", r);
+        }
+    }
+
+    // in a closed ring every endpoint is shared by exactly two segments
+    let key = |(x, y): (f32, f32)| ((x * 1000.0).round() as i64, (y * 1000.0).round() as i64);
+    let mut ends: Vec<_> = segments
+        .iter()
+        .flat_map(|s| vec![key(s.from), key(s.to)])
+        .collect();
+    ends.sort_unstable();
+    for pair in ends.chunks(2) {
+        assert_eq!(pair[0], pair[1]);
+    }
+}
@@ -16,6 +16,41 @@ pub fn draw_line(
     x2: isize,
     y2: isize,
     fun: impl Fn(f32) -> f32,
+) {
+    rasterize_line(x1, y1, x2, y2, |x, y| {
+        map[(x as _, y as _)] = fun(map[(x as _, y as _)]);
+    });
+}
+
+/// Draws a line like [`draw_line`](./fn.draw_line.html), but on a tileable map.
+///
+/// The coordinates may lie outside of the map, every cell of the line is wrapped around the
+/// edges. This way lines crossing an edge are drawn the short way instead of across the map.
+#[inline]
+#[cfg(test)]
+pub fn draw_line_wrapped(
+    map: &mut Map,
+    x1: isize,
+    y1: isize,
+    x2: isize,
+    y2: isize,
+    fun: impl Fn(f32) -> f32,
+) {
+    let (width, height) = (map.width() as isize, map.height() as isize);
+    rasterize_line(x1, y1, x2, y2, |x, y| {
+        let xy = (x.rem_euclid(width) as usize, y.rem_euclid(height) as usize);
+        map[xy] = fun(map[xy]);
+    });
+}
+
+/// Calls `plot` for every cell on the line from `(x1, y1)` to `(x2, y2)`
+#[inline]
+pub fn rasterize_line(
+    x1: isize,
+    y1: isize,
+    x2: isize,
+    y2: isize,
+    mut plot: impl FnMut(isize, isize),
 ) {
     let sign = |x| if x > 0 { 1 } else { -1 };
 
@@ -35,7 +70,7 @@ pub fn draw_line(
     let (mut x, mut y) = (x1, y1);
 
     for _ in 0..dx {
-        map[(x as _, y as _)] = fun(map[(x as _, y as _)]);
+        plot(x, y);
 
         while d >= 0 {
             if swap {
@@ -54,5 +89,5 @@ pub fn draw_line(
         d += 2 * dy;
     }
 
-    map[(x as _, y as _)] = fun(map[(x as _, y as _)]);
+    plot(x, y);
 }
@@ -1,17 +1,161 @@
 use crate::map::*;
 use rayon::prelude::*;
+use std::ops::*;
 
-/// finds the flow target for each position on the map and returns them in a `Vec`
-pub fn find_targets(map: &Map, range: usize) -> Vec<Vec<(usize, usize)>> {
+/// Flow target of each cell of a map, which is the lowest cell within the range of the search.
+///
+/// The targets are stored as `u32` cell indices in a grid.
+#[derive(Clone)]
+pub struct FlowField {
+    targets: Grid<u32>,
+    range: usize,
+    /// relative positions of the cells within the range of the search
+    reach: Vec<(isize, isize)>,
+    wrap: bool,
+}
+
+impl FlowField {
+    /// Flow field of the given targets, which all lie closer to their cells than `range`
+    pub fn from_targets(targets: Grid<u32>, range: usize, wrap: bool) -> Self {
+        Self {
+            targets,
+            range,
+            reach: points(range, &|x, y| x * x + y * y < (range * range) as isize),
+            wrap,
+        }
+    }
+
+    #[allow(unused)]
+    pub fn width(&self) -> usize {
+        self.targets.width()
+    }
+
+    #[allow(unused)]
+    pub fn height(&self) -> usize {
+        self.targets.height()
+    }
+
+    /// Targets are closer to their cells than this, in each direction
+    pub fn range(&self) -> usize {
+        self.range
+    }
+
+    /// Whether targets can lie across the edges of the map
+    pub fn is_wrapped(&self) -> bool {
+        self.wrap
+    }
+
+    #[inline(always)]
+    pub fn flatten_xy(&self, x: usize, y: usize) -> usize {
+        self.targets.flatten_xy(x, y)
+    }
+
+    #[inline(always)]
+    pub fn unflatten(&self, idx: usize) -> (usize, usize) {
+        self.targets.unflatten(idx)
+    }
+
+    /// Grid of the target of each cell
+    #[allow(unused)]
+    pub fn targets(&self) -> &Grid<u32> {
+        &self.targets
+    }
+
+    /// Coordinates of the cell water flows to from `(x, y)`
+    #[inline]
+    pub fn target(&self, x: usize, y: usize) -> (usize, usize) {
+        self.unflatten(self[(x, y)] as usize)
+    }
+
+    /// Whether the cell is its own target, because there is nothing lower within range
+    #[inline]
+    pub fn is_sink(&self, x: usize, y: usize) -> bool {
+        self[(x, y)] as usize == self.flatten_xy(x, y)
+    }
+
+    /// Iterates over the cells water flows through after leaving `(x, y)`, ending with a sink
+    #[allow(unused)]
+    pub fn downstream(&self, x: usize, y: usize) -> impl Iterator<Item = (usize, usize)> + '_ {
+        let mut idx = self.flatten_xy(x, y);
+        let targets = self.targets.values();
+        let mut steps = 0;
+        std::iter::from_fn(move || {
+            let next = targets[idx] as usize;
+            // cells of the same height can target each other on flat ground, so the path is
+            // never followed for longer than there are cells
+            if next == idx || steps == targets.len() {
+                return None;
+            }
+            steps += 1;
+            idx = next;
+            Some(self.unflatten(idx))
+        })
+    }
+
+    /// Iterates over the cells that directly flow into `(x, y)`
+    #[allow(unused)]
+    pub fn upstream(&self, x: usize, y: usize) -> impl Iterator<Item = (usize, usize)> + '_ {
+        let idx = self.flatten_xy(x, y) as u32;
+        // targets are always within range, so only the cells in range have to be checked
+        self.reach
+            .iter()
+            .filter(|&&offset| offset != (0, 0))
+            .filter_map(move |&(dx, dy)| self.targets.offset(x, y, dx, dy, self.wrap))
+            .filter(move |&xy| self[xy] == idx)
+    }
+}
+
+impl Index<(usize, usize)> for FlowField {
+    type Output = u32;
+
+    fn index(&self, xy: (usize, usize)) -> &Self::Output {
+        &self.targets[xy]
+    }
+}
+
+impl IndexMut<(usize, usize)> for FlowField {
+    fn index_mut(&mut self, xy: (usize, usize)) -> &mut Self::Output {
+        &mut self.targets[xy]
+    }
+}
+
+/// finds the flow target for each position on the map
+///
+/// With `wrap`, the map is treated as tileable and targets can lie across the edges.
+pub fn find_targets(map: &Map, range: usize, wrap: bool) -> FlowField {
     assert!(range >= 1);
     assert!(range < map.width());
     assert!(range < map.height());
+    // cell indices have to fit into the targets
+    assert!(map.width() * map.height() <= u32::MAX as usize);
+
+    let targets = if wrap {
+        find_wrapped_targets(map, range)
+    } else {
+        let threads = if map.height() < 512 {
+            1
+        } else {
+            num_cpus::get()
+        };
+        // rounded up, so that there aren't more chunks than threads
+        let chunk_size = map.height().div_ceil(threads);
 
+        chunked_targets(map, range, chunk_size)
+    };
+
+    FlowField::from_targets(targets, range, wrap)
+}
+
+/// finds the targets, processing rows in chunks of `chunk_size` in parallel
+fn chunked_targets(map: &Map, range: usize, chunk_size: usize) -> Grid<u32> {
     let (width, height) = (map.width(), map.height());
-    let mut targets = vec![vec![(0, 0); height]; width];
+    let mut targets = Grid::new(width, height);
 
     let circle = |x, y| x * x + y * y < (range * range) as isize;
-    let within_range = |x, y, (ox, oy)| circle(x as isize - ox as isize, y as isize - oy as isize);
+    let within_range = |x, y, t: u32| {
+        let (tx, ty) = map.unflatten(t as usize);
+        circle(x as isize - tx as isize, y as isize - ty as isize)
+    };
 
     // points to check that neighbors didn't check with 4 different cases
     let rest_points = [
@@ -23,63 +167,44 @@ pub fn find_targets(map: &Map, range: usize) -> Vec<Vec<(usize, usize)>> {
         }),
     ];
 
-    // finding targets of the upper and lower border positions
-    for x in 0..width {
-        for y in (0..range).chain((height - range)..height) {
-            targets[x][y] = next_target(map, x, y, &rest_points[0], true);
-        }
-    }
-
-    let threads = if width < 512 { 1 } else { num_cpus::get() };
-    let chunk_size = width / threads;
-
     targets
-        .par_iter_mut()
-        .chunks(chunk_size)
+        .values_mut()
+        .par_chunks_mut(chunk_size * width)
         .enumerate()
-        .for_each(|(chunk_id, mut chunk)| {
+        .for_each(|(chunk_id, chunk)| {
             let chunk_offset = chunk_id * chunk_size;
 
-            // calculate the border areas naively
-            for x in 0..range {
-                for y in 0..height {
-                    chunk[x][y] = next_target(map, x + chunk_offset, y, &rest_points[0], true);
-                }
-            }
-
-            let x_end;
+            for local_y in 0..chunk.len() / width {
+                let y = local_y + chunk_offset;
+                let row = local_y * width;
 
-            if chunk_id == threads - 1 {
-                for x in chunk.len() - range..chunk.len() {
-                    for y in 0..height {
-                        chunk[x][y] = next_target(map, x + chunk_offset, y, &rest_points[0], true);
+                for x in 0..width {
+                    if local_y < range || y + range >= height || x < range || x + range >= width {
+                        // calculate the border areas naively, at the start of a chunk the targets
+                        // of the previous row aren't known yet, and at the edges of the map the
+                        // neighbors need to be bounds checked
+                        let (tx, ty) = next_target(map, x, y, &rest_points[0], true);
+                        chunk[row + x] = map.flatten_xy(tx, ty) as u32;
+                        continue;
                     }
-                }
-                x_end = chunk.len() - range;
-            } else {
-                x_end = chunk.len();
-            }
-
-            for local_x in range..x_end {
-                let x = local_x + chunk_offset;
 
-                for y in range..(height - range) {
                     let mut lowest = f32::MAX;
                     let mut target = (x, y);
                     let mut rest_idx = 0;
 
                     // check if target of x neighbor can be used
-                    let tx = chunk[local_x - 1][y];
+                    let tx = chunk[row + x - 1];
                     if within_range(x, y, tx) {
                         rest_idx += 1;
-                        lowest = map[tx];
-                        target = tx;
+                        target = map.unflatten(tx as usize);
+                        lowest = map[target];
                     }
 
                     // check if target of y neighbor can be used
-                    let ty = chunk[local_x][y - 1];
+                    let ty = chunk[row - width + x];
                     if within_range(x, y, ty) {
                         rest_idx += 2;
+                        let ty = map.unflatten(ty as usize);
                         let h = map[ty];
                         if h < lowest {
                             lowest = h;
@@ -95,7 +220,7 @@ pub fn find_targets(map: &Map, range: usize) -> Vec<Vec<(usize, usize)>> {
                         target = t;
                     }
 
-                    chunk[local_x][y] = target;
+                    chunk[row + x] = map.flatten_xy(target.0, target.1) as u32;
                 }
             }
         });
@@ -103,6 +228,24 @@ pub fn find_targets(map: &Map, range: usize) -> Vec<Vec<(usize, usize)>> {
     targets
 }
 
+/// Finds the targets of a tileable map, by padding it with `range` cells from the opposite edges
+/// on each side and wrapping the targets found on the padded map back
+fn find_wrapped_targets(map: &Map, range: usize) -> Grid<u32> {
+    let (width, height) = (map.width(), map.height());
+    let wrap = |v: usize, len: usize| (v + len - range) % len;
+
+    let mut padded = Map::new(width + 2 * range, height + 2 * range);
+    padded.map_coords(|x, y, _| map[(wrap(x, width), wrap(y, height))]);
+    let targets = find_targets(&padded, range, false);
+
+    let mut wrapped = Grid::new(width, height);
+    wrapped.map_coords(|x, y, _| {
+        let (tx, ty) = targets.target(x + range, y + range);
+        map.flatten_xy(wrap(tx, width), wrap(ty, height)) as u32
+    });
+    wrapped
+}
+
 /// Finds points within a range that satisfy a property
 fn points(range: usize, inside: &dyn Fn(isize, isize) -> bool) -> Vec<(isize, isize)> {
     let mut positions = Vec::with_capacity(range * range);
@@ -160,26 +303,97 @@ fn next_target(
     (nx, ny)
 }
 
-/// Check if the efficient DP solution does find the local minimum of each point correctly
+/// Check if the efficient DP solution does find the local minimum of each point correctly, also
+/// for maps that aren't square, maps barely wider than the range, and chunks of any size
 #[test]
 fn check_dp_solution() {
     use crate::simplex::*;
 
-    let size = 128;
-    let map = simplex_map(size, size);
     let range = 6;
-    let targets = find_targets(&map, range);
     let circle = |x, y| x * x + y * y < (range * range) as isize;
     let points = points(range, &circle);
 
+    for &(width, height) in [(128, 128), (150, 67), (40, 190), (7, 90), (90, 7)].iter() {
+        let map = simplex_map(width, height);
+
+        for &chunk_size in [height, 37, 1].iter() {
+            let targets = chunked_targets(&map, range, chunk_size);
+
+            for x in 0..width {
+                for y in 0..height {
+                    let target = next_target(&map, x, y, &points, true);
+
+                    // Check if found _a_ minimum, not the specific minimum the target function
+                    // would have found. If the height is the same, but the coordinates are
+                    // different, there are multiple valid solutions.
+                    let found = map.unflatten(targets[(x, y)] as usize);
+                    assert_eq!(map[target], map[found]);
+                }
+            }
+        }
+    }
+}
+
+/// Check that the targets of a tileable map move along when the map is shifted across its edges
+#[test]
+fn check_wrapped_targets() {
+    use crate::simplex::*;
+
+    let size = 64;
+    let shift = 23;
+    let map = tileable_map(size, size, &PRESETS[0], 0);
+    let mut shifted = map.clone();
+    shifted.map_coords(|x, y, _| map[((x + shift) % size, (y + shift) % size)]);
+
+    let targets = find_targets(&map, 6, true);
+    let shifted_targets = find_targets(&shifted, 6, true);
+
     for x in 0..size {
         for y in 0..size {
-            let target = next_target(&map, x, y, &points, true);
+            let (tx, ty) = shifted_targets.target(x, y);
+            let unshifted = ((tx + shift) % size, (ty + shift) % size);
+            assert_eq!(
+                unshifted,
+                targets.target((x + shift) % size, (y + shift) % size)
+            );
+        }
+    }
+}
+
+/// Check that the upstream and downstream cells agree with the targets
+#[test]
+fn check_flow_iterators() {
+    use crate::simplex::*;
+
+    for &wrap in [false, true].iter() {
+        let map = simplex_map(80, 60);
+        let targets = find_targets(&map, 6, wrap);
 
-            // Check if found _a_ minimum, not the specific minimum the target function would have
-            // found. If the height is the same, but the coordinates are different, there are
-            // multiple valid solutions.
-            assert_eq!(map[target], map[targets[x][y]]);
+        for x in 0..map.width() {
+            for y in 0..map.height() {
+                for (ux, uy) in targets.upstream(x, y) {
+                    assert_eq!(targets.target(ux, uy), (x, y));
+                }
+
+                let mut last = (x, y);
+                for cell in targets.downstream(x, y) {
+                    assert_eq!(targets.target(last.0, last.1), cell);
+                    assert!(map[cell] <= map[last]);
+                    last = cell;
+                }
+                assert!(targets.is_sink(last.0, last.1));
+            }
         }
+
+        // every cell except the sinks is upstream of exactly one cell
+        let upstream: usize = (0..map.width())
+            .flat_map(|x| (0..map.height()).map(move |y| (x, y)))
+            .map(|(x, y)| targets.upstream(x, y).count())
+            .sum();
+        let sinks = (0..map.width())
+            .flat_map(|x| (0..map.height()).map(move |y| (x, y)))
+            .filter(|&(x, y)| targets.is_sink(x, y))
+            .count();
+        assert_eq!(upstream + sinks, map.width() * map.height());
     }
 }
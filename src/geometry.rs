@@ -15,3 +15,69 @@ impl Triangle {
         (vec[1] - vec[0]).cross(vec[2] - vec[0]).normalize()
     }
 }
+
+/// Distance of the point `p` to the line segment from `a` to `b` in two dimensions
+pub fn distance_to_segment(
+    (px, py): (f32, f32),
+    (ax, ay): (f32, f32),
+    (bx, by): (f32, f32),
+) -> f32 {
+    let (dx, dy) = (bx - ax, by - ay);
+    let len2 = dx * dx + dy * dy;
+    let t = if len2 > 0.0 {
+        (((px - ax) * dx + (py - ay) * dy) / len2).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    (px - ax - t * dx).hypot(py - ay - t * dy)
+}
+
+/// Buckets of indices of points, to quickly find the points close to a position
+pub struct SpatialGrid {
+    cell: f32,
+    width: usize,
+    height: usize,
+    buckets: Vec<Vec<usize>>,
+}
+
+impl SpatialGrid {
+    pub fn new(width: f32, height: f32, cell: f32) -> Self {
+        let width = (width / cell).ceil() as usize + 1;
+        let height = (height / cell).ceil() as usize + 1;
+        Self {
+            cell,
+            width,
+            height,
+            buckets: vec![Vec::new(); width * height],
+        }
+    }
+
+    fn bucket(&self, x: f32, y: f32) -> (usize, usize) {
+        let bx = ((x / self.cell) as usize).min(self.width - 1);
+        let by = ((y / self.cell) as usize).min(self.height - 1);
+        (bx, by)
+    }
+
+    pub fn insert(&mut self, x: f32, y: f32, idx: usize) {
+        let (bx, by) = self.bucket(x, y);
+        self.buckets[bx + by * self.width].push(idx);
+    }
+
+    /// Indices of all points within `radius` of `(x, y)`, and possibly some points a bit further
+    /// away
+    pub fn near(&self, x: f32, y: f32, radius: f32) -> impl Iterator<Item = usize> + '_ {
+        let reach = (radius / self.cell).ceil() as usize;
+        let (bx, by) = self.bucket(x, y);
+
+        let xs = bx.saturating_sub(reach)..=(bx + reach).min(self.width - 1);
+        let ys = by.saturating_sub(reach)..=(by + reach).min(self.height - 1);
+        xs.flat_map(move |gx| ys.clone().map(move |gy| gx + gy * self.width))
+            .flat_map(move |bucket| self.buckets[bucket].iter().cloned())
+    }
+
+    /// Checks whether `pred` holds for any point within `radius` of `(x, y)` (and possibly some
+    /// points a bit further away)
+    pub fn any_near(&self, x: f32, y: f32, radius: f32, pred: impl Fn(usize) -> bool) -> bool {
+        self.near(x, y, radius).any(pred)
+    }
+}
@@ -0,0 +1,305 @@
+use crate::buffer::*;
+use rayon::prelude::*;
+use std::ops::*;
+
+/// Relative positions of the 4 neighbors sharing an edge with a cell
+const NEIGHBORS_4: [(isize, isize); 4] = [(-1, 0), (1, 0), (0, -1), (0, 1)];
+
+/// Relative positions of the 8 neighbors sharing an edge or a corner with a cell
+const NEIGHBORS_8: [(isize, isize); 8] = [
+    (-1, -1),
+    (0, -1),
+    (1, -1),
+    (-1, 0),
+    (1, 0),
+    (-1, 1),
+    (0, 1),
+    (1, 1),
+];
+
+/// Generic matrix type with a fixed width and height, stored row by row.
+///
+/// It holds any kind of cells of a terrain, like heights in a [`Map`](../map/type.Map.html),
+/// flags in a [`Mask`](../combine/type.Mask.html) or cell indices.
+pub struct Grid<T: Zeroable> {
+    width: usize,
+    height: usize,
+    buffer: Buffer<T>,
+}
+
+impl<T: Zeroable> Grid<T> {
+    /// Grid with all of its cells zeroed
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            buffer: Buffer::zeroed(width * height),
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Calls `fun` in parallel for bands of `rows` rows, with the range of rows in the band and
+    /// their values
+    pub fn par_bands_mut<F>(&mut self, rows: usize, fun: F)
+    where
+        F: Send + Sync + Fn(Range<usize>, &mut [T]),
+    {
+        let (width, height) = (self.width, self.height);
+        self.buffer
+            .par_chunks_mut(rows * width)
+            .enumerate()
+            .for_each(|(band, values)| {
+                let y = band * rows;
+                fun(y..(y + rows).min(height), values)
+            });
+    }
+
+    /// Values of all cells, row after row
+    pub fn values(&self) -> &[T] {
+        &self.buffer
+    }
+
+    /// Values of all cells, row after row
+    pub fn values_mut(&mut self) -> &mut [T] {
+        &mut self.buffer
+    }
+
+    #[inline(always)]
+    pub fn flatten_xy(&self, x: usize, y: usize) -> usize {
+        x + y * self.width
+    }
+
+    /// Inverse of [`flatten_xy`](#method.flatten_xy)
+    #[inline(always)]
+    pub fn unflatten(&self, idx: usize) -> (usize, usize) {
+        (idx % self.width, idx / self.width)
+    }
+
+    /// Whether `(x, y)` lies within the grid
+    #[inline]
+    pub fn contains(&self, x: isize, y: isize) -> bool {
+        x >= 0 && y >= 0 && x < self.width as isize && y < self.height as isize
+    }
+
+    /// Returns the cell at `(x, y)`, or `None` outside of the grid
+    #[inline]
+    #[allow(unused)]
+    pub fn get(&self, x: isize, y: isize) -> Option<&T> {
+        if self.contains(x, y) {
+            Some(&self[(x as usize, y as usize)])
+        } else {
+            None
+        }
+    }
+
+    /// Returns the cell at `(x, y)`, or `None` outside of the grid
+    #[inline]
+    #[allow(unused)]
+    pub fn get_mut(&mut self, x: isize, y: isize) -> Option<&mut T> {
+        if self.contains(x, y) {
+            Some(&mut self[(x as usize, y as usize)])
+        } else {
+            None
+        }
+    }
+
+    /// Returns the coordinates of the cell `(dx, dy)` away from `(x, y)`.
+    ///
+    /// With `wrap` the grid is treated as a torus, so coordinates wrap around the edges. Otherwise
+    /// `None` is returned outside of the grid.
+    #[inline]
+    pub fn offset(
+        &self,
+        x: usize,
+        y: usize,
+        dx: isize,
+        dy: isize,
+        wrap: bool,
+    ) -> Option<(usize, usize)> {
+        grid_offset(self.width, self.height, x, y, dx, dy, wrap)
+    }
+
+    /// Iterates over the coordinates of the 4 cells sharing an edge with `(x, y)`, leaving out the
+    /// ones outside of the grid unless it `wrap`s around
+    pub fn neighbors4(
+        &self,
+        x: usize,
+        y: usize,
+        wrap: bool,
+    ) -> impl Iterator<Item = (usize, usize)> {
+        self.neighbors(x, y, &NEIGHBORS_4, wrap)
+    }
+
+    /// Iterates over the coordinates of the 8 cells sharing an edge or a corner with `(x, y)`,
+    /// leaving out the ones outside of the grid unless it `wrap`s around
+    pub fn neighbors8(
+        &self,
+        x: usize,
+        y: usize,
+        wrap: bool,
+    ) -> impl Iterator<Item = (usize, usize)> {
+        self.neighbors(x, y, &NEIGHBORS_8, wrap)
+    }
+
+    /// Iterates over the coordinates of the cells at most `radius` cells away from `(x, y)`,
+    /// except for the cell itself, leaving out the ones outside of the grid unless it `wrap`s
+    /// around
+    #[allow(unused)]
+    pub fn neighbors_within(
+        &self,
+        x: usize,
+        y: usize,
+        radius: usize,
+        wrap: bool,
+    ) -> impl Iterator<Item = (usize, usize)> {
+        let (width, height) = (self.width, self.height);
+        let r = radius as isize;
+        (-r..=r)
+            .flat_map(move |dy| (-r..=r).map(move |dx| (dx, dy)))
+            .filter(move |&(dx, dy)| (dx, dy) != (0, 0) && dx * dx + dy * dy <= r * r)
+            .filter_map(move |(dx, dy)| grid_offset(width, height, x, y, dx, dy, wrap))
+    }
+
+    fn neighbors(
+        &self,
+        x: usize,
+        y: usize,
+        offsets: &'static [(isize, isize)],
+        wrap: bool,
+    ) -> impl Iterator<Item = (usize, usize)> {
+        let (width, height) = (self.width, self.height);
+        offsets
+            .iter()
+            .filter_map(move |&(dx, dy)| grid_offset(width, height, x, y, dx, dy, wrap))
+    }
+}
+
+impl<T: Zeroable + Copy> Grid<T> {
+    /// maps each entry of the grid to a new value
+    pub fn map<F>(&mut self, fun: F)
+    where
+        F: Send + Sync + Fn(T) -> T,
+    {
+        self.map_coords(|_, _, v| fun(v))
+    }
+
+    /// maps each entry of the grid, together with its coordinates to a new value
+    pub fn map_coords<F>(&mut self, fun: F)
+    where
+        F: Send + Sync + Fn(usize, usize, T) -> T,
+    {
+        self.buffer
+            .par_chunks_mut(self.width)
+            .enumerate()
+            .for_each(|(y, chunk)| {
+                for (x, v) in chunk.iter_mut().enumerate() {
+                    *v = fun(x, y, *v);
+                }
+            });
+    }
+
+    /// Grid with every cell set to `value`
+    pub fn filled(width: usize, height: usize, value: T) -> Self {
+        let mut grid = Self::new(width, height);
+        grid.map(|_| value);
+        grid
+    }
+
+    /// Returns the value at `(x, y)`, with coordinates outside of the grid clamped to the border
+    #[inline]
+    pub fn get_clamped(&self, x: isize, y: isize) -> T {
+        let x = x.clamp(0, self.width as isize - 1) as usize;
+        let y = y.clamp(0, self.height as isize - 1) as usize;
+        self[(x, y)]
+    }
+}
+
+impl<T: Zeroable + Copy> Clone for Grid<T> {
+    fn clone(&self) -> Self {
+        Self {
+            width: self.width,
+            height: self.height,
+            buffer: self.buffer.clone(),
+        }
+    }
+}
+
+impl<T: Zeroable> Index<(usize, usize)> for Grid<T> {
+    type Output = T;
+
+    fn index(&self, (x, y): (usize, usize)) -> &Self::Output {
+        &self.buffer[self.flatten_xy(x, y)]
+    }
+}
+
+impl<T: Zeroable> IndexMut<(usize, usize)> for Grid<T> {
+    fn index_mut(&mut self, (x, y): (usize, usize)) -> &mut Self::Output {
+        let idx = self.flatten_xy(x, y);
+        &mut self.buffer[idx]
+    }
+}
+
+/// Same as [`Grid::offset`](./struct.Grid.html#method.offset), for any grid of the given size
+#[inline]
+pub fn grid_offset(
+    width: usize,
+    height: usize,
+    x: usize,
+    y: usize,
+    dx: isize,
+    dy: isize,
+    wrap: bool,
+) -> Option<(usize, usize)> {
+    let (w, h) = (width as isize, height as isize);
+    let (x, y) = (x as isize + dx, y as isize + dy);
+    if wrap {
+        Some((x.rem_euclid(w) as usize, y.rem_euclid(h) as usize))
+    } else if x < 0 || y < 0 || x >= w || y >= h {
+        None
+    } else {
+        Some((x as usize, y as usize))
+    }
+}
+
+/// Check the bounds-checked accessors and the neighborhoods at the corners and across the edges
+#[test]
+fn test_grid_neighborhoods() {
+    let mut grid = Grid::<u32>::new(5, 4);
+    grid.map_coords(|x, y, _| (x + 10 * y) as u32);
+
+    assert_eq!(grid.get(4, 3), Some(&34));
+    assert_eq!(grid.get(5, 0), None);
+    assert_eq!(grid.get(0, -1), None);
+    *grid.get_mut(1, 2).unwrap() = 99;
+    assert_eq!(grid[(1, 2)], 99);
+    assert_eq!(grid.get_clamped(-3, 7), 30);
+
+    let sorted = |cells: &mut dyn Iterator<Item = (usize, usize)>| {
+        let mut cells: Vec<_> = cells.collect();
+        cells.sort_unstable();
+        cells
+    };
+    assert_eq!(
+        sorted(&mut grid.neighbors4(0, 0, false)),
+        vec![(0, 1), (1, 0)]
+    );
+    assert_eq!(
+        sorted(&mut grid.neighbors4(0, 0, true)),
+        vec![(0, 1), (0, 3), (1, 0), (4, 0)]
+    );
+    assert_eq!(grid.neighbors8(4, 3, false).count(), 3);
+    assert_eq!(grid.neighbors8(2, 1, false).count(), 8);
+    assert_eq!(grid.neighbors8(0, 0, true).count(), 8);
+    assert!(grid.neighbors8(0, 0, true).any(|xy| xy == (4, 3)));
+
+    // the 12 cells within a radius of 2, minus the 4 left of the grid and the 1 below it
+    assert_eq!(grid.neighbors_within(0, 2, 2, false).count(), 7);
+    assert_eq!(grid.neighbors_within(2, 2, 1, true).count(), 4);
+}
@@ -1,10 +1,318 @@
-use crate::map::*;
+use crate::{buffer::*, flow::*, map::*};
+use rayon::prelude::*;
 use std::collections::*;
+use std::sync::atomic::{AtomicU32, Ordering::*};
 
-pub fn lake_map(map: &Map, rivers: &Map, targets: &Vec<Vec<(usize, usize)>>, ocean: f32) -> Map {
+/// Number of rows of the lake map that are labelled together
+const LAKE_BAND: usize = 64;
+
+/// Finds the lakes and the ocean, and returns a map of their water levels.
+///
+/// If the targets wrap around the edges, the map is treated as tileable, otherwise everything
+/// outside of it counts as ocean.
+///
+/// The lakes are flooded from their origins one after another, each one stopping at the water
+/// found before it. To do this in parallel, all lakes are flooded at once first, each one giving up
+/// as soon as it runs into a cell an earlier lake has reached. Going through the lakes in order,
+/// see [`resolve_floods`](./fn.resolve_floods.html), the floods are cut off where they reach water
+/// of the earlier lakes, and the ones which ran into an earlier lake before are flooded again. The
+/// result is the same as flooding them one after another.
+pub fn lake_map(map: &Map, rivers: &Map, targets: &FlowField, ocean: f32) -> Map {
+    let width = map.width();
+    let height = map.height();
+    let wrap = targets.is_wrapped();
+    assert!(
+        width * height <= u32::MAX as usize,
+        "map too large for 32 bit cell indices"
+    );
+
+    let mut lakes = Map::new(width, height);
+    lakes.par_bands_mut(LAKE_BAND, |rows, values| {
+        for (y, values) in rows.zip(values.chunks_mut(width)) {
+            for (x, value) in values.iter_mut().enumerate() {
+                if map[(x, y)] <= ocean {
+                    *value = 1.0;
+                }
+            }
+        }
+    });
+
+    let mut origins: Vec<u32> = (0..height)
+        .into_par_iter()
+        .flat_map_iter(|y| {
+            (0..width).filter_map(move |x| {
+                let xy = (x, y);
+                if map[xy] > ocean && targets.is_sink(x, y) && rivers[xy] > 100.0 {
+                    Some(map.flatten_xy(x, y) as u32)
+                } else {
+                    None
+                }
+            })
+        })
+        .collect();
+    // earlier lakes are the ones further to the left, then further up
+    origins.sort_by_key(|&idx| {
+        let (x, y) = map.unflatten(idx as usize);
+        (x, y)
+    });
+
+    // earliest lake whose flood reached each cell, as its rank, 0 meaning no lake
+    let owners = Grid::<AtomicU32>::new(width, height);
+
+    let floods: Vec<Vec<u32>> = origins
+        .par_iter()
+        .enumerate()
+        .map(|(i, &origin)| {
+            let claim = (&owners, rank(origins.len(), i));
+            flood(map, targets, &lakes, ocean, origin as usize, Some(claim))
+        })
+        .collect();
+
+    resolve_floods(map, targets, &mut lakes, ocean, &origins, &owners, floods);
+    label_lakes(map, &mut lakes, ocean, wrap);
+    lakes
+}
+
+/// Rank of the `i`th of `count` lakes, counted from the back so that earlier lakes have a higher
+/// rank and 0 means no lake
+fn rank(count: usize, i: usize) -> u32 {
+    (count - i) as u32
+}
+
+/// Goes through the `floods` of the lakes at `origins` in order and sets their cells in `lakes`,
+/// so that each lake ends up like flooded after all the earlier ones.
+///
+/// A flood is the same as the one after the earlier lakes up to the first cell which is water by
+/// now or which an earlier lake reached as well, as marked in `owners`. If that cell is water, the
+/// lake stopped there and the rest is dropped, otherwise the lake is flooded again.
+fn resolve_floods(
+    map: &Map,
+    targets: &FlowField,
+    lakes: &mut Map,
+    ocean: f32,
+    origins: &[u32],
+    owners: &Grid<AtomicU32>,
+    floods: Vec<Vec<u32>>,
+) {
+    for (i, mut points) in floods.into_iter().enumerate() {
+        // an earlier lake which was flooded again didn't mark its cells, so the water has to be
+        // checked as well
+        let overlap = points.iter().position(|&point| {
+            let xy = map.unflatten(point as usize);
+            owners[xy].load(Relaxed) != rank(origins.len(), i) || lakes[xy] > 0.0
+        });
+
+        let points = match overlap {
+            None => points,
+            // the earlier lake is water now, so this one stopped when it reached it
+            Some(pos) if lakes[map.unflatten(points[pos] as usize)] > 0.0 => {
+                points.truncate(pos);
+                points
+            }
+            // all of the earlier lakes are known now, so the lake can be flooded for good
+            Some(_) => flood(map, targets, lakes, ocean, origins[i] as usize, None),
+        };
+        for point in points.into_iter() {
+            lakes[map.unflatten(point as usize)] = 1.0;
+        }
+    }
+}
+
+/// Floods the lake at `origin` from the lowest of its border cells up until it spills into
+/// lower terrain or other water, and returns the cells it covers in the order they were reached.
+///
+/// With a `claim`, the cells are marked with the rank of the lake. If a cell was already reached
+/// by a lake with a higher rank, the flood gives up and the cell is the last one returned.
+fn flood(
+    map: &Map,
+    targets: &FlowField,
+    lakes: &Map,
+    ocean: f32,
+    origin: usize,
+    claim: Option<(&Grid<AtomicU32>, u32)>,
+) -> Vec<u32> {
+    let wrap = targets.is_wrapped();
+    let mut queue = BinaryHeap::new();
+    let mut points = Vec::new();
+    let mut visited = HashSet::new();
+
+    macro_rules! enq {
+        ($xy:expr) => {{
+            if let Some(xy) = $xy {
+                if lakes[xy] > 0.0 {
+                    true
+                } else {
+                    let idx = map.flatten_xy(xy.0, xy.1) as u32;
+                    if visited.insert(idx) {
+                        queue.push(Point {
+                            x: xy.0,
+                            y: xy.1,
+                            z: map[xy],
+                        });
+                        points.push(idx);
+
+                        if let Some((owners, rank)) = claim {
+                            if owners[xy].fetch_max(rank, Relaxed) > rank {
+                                return points;
+                            }
+                        }
+                    }
+                    false
+                }
+            } else {
+                // everything outside of the map counts as ocean
+                true
+            }
+        }};
+    }
+
+    if enq!(Some(map.unflatten(origin))) {
+        return points;
+    }
+
+    while let Some(Point { x, y, z }) = queue.pop() {
+        if z <= ocean {
+            break;
+        }
+
+        if enq!(map.offset(x, y, -1, 0, wrap))
+            || enq!(map.offset(x, y, 1, 0, wrap))
+            || enq!(map.offset(x, y, 0, -1, wrap))
+            || enq!(map.offset(x, y, 0, 1, wrap))
+            || enq!(Some(targets.target(x, y)))
+        {
+            break;
+        }
+    }
+
+    points
+}
+
+/// Sets every cell of the connected bodies of water in `lakes` to the highest terrain height
+/// within them, and removes the ones which are too small.
+///
+/// The cells are grouped with a parallel union-find, where each cell points to a cell of the same
+/// group with a lower index, so that the root of every group is its first cell.
+fn label_lakes(map: &Map, lakes: &mut Map, ocean: f32, wrap: bool) {
     let width = map.width();
     let height = map.height();
 
+    let parents = Buffer::<AtomicU32>::zeroed(width * height);
+    parents
+        .par_iter()
+        .enumerate()
+        .for_each(|(idx, parent)| parent.store(idx as u32, Relaxed));
+
+    (0..height).into_par_iter().for_each(|y| {
+        for x in 0..width {
+            if lakes[(x, y)] <= 0.0 {
+                continue;
+            }
+            for &(dx, dy) in [(1, 0), (0, 1)].iter() {
+                if let Some((nx, ny)) = map.offset(x, y, dx, dy, wrap) {
+                    if lakes[(nx, ny)] > 0.0 {
+                        union(
+                            &parents,
+                            map.flatten_xy(x, y) as u32,
+                            map.flatten_xy(nx, ny) as u32,
+                        );
+                    }
+                }
+            }
+        }
+    });
+
+    // area and highest point of each group, stored at its root
+    let area = Buffer::<AtomicU32>::zeroed(width * height);
+    let max = Buffer::<AtomicU32>::zeroed(width * height);
+
+    (0..height).into_par_iter().for_each(|y| {
+        for x in 0..width {
+            if lakes[(x, y)] > 0.0 {
+                let root = find(&parents, map.flatten_xy(x, y) as u32) as usize;
+                area[root].fetch_add(1, Relaxed);
+                max[root].fetch_max(ordered_bits(map[(x, y)]), Relaxed);
+            }
+        }
+    });
+
+    lakes.par_bands_mut(LAKE_BAND, |rows, values| {
+        let offset = rows.start * width;
+        for (idx, value) in values.iter_mut().enumerate() {
+            if *value <= 0.0 {
+                continue;
+            }
+            let root = find(&parents, (offset + idx) as u32) as usize;
+            *value = if area[root].load(Relaxed) > 10 {
+                from_ordered_bits(max[root].load(Relaxed)).max(ocean)
+            } else {
+                0.0
+            };
+        }
+    });
+}
+
+/// Returns the root of the group of `idx`, halving the path to it on the way
+fn find(parents: &[AtomicU32], mut idx: u32) -> u32 {
+    loop {
+        let parent = parents[idx as usize].load(Relaxed);
+        if parent == idx {
+            return idx;
+        }
+        let grandparent = parents[parent as usize].load(Relaxed);
+        // if another thread changed the parent in the meantime, the path just isn't shortened
+        let _ = parents[idx as usize].compare_exchange_weak(parent, grandparent, Relaxed, Relaxed);
+        idx = grandparent;
+    }
+}
+
+/// Merges the groups of `a` and `b`, by attaching the root with the higher index to the other one
+fn union(parents: &[AtomicU32], a: u32, b: u32) {
+    let (mut a, mut b) = (a, b);
+    loop {
+        a = find(parents, a);
+        b = find(parents, b);
+        if a == b {
+            return;
+        }
+        let (low, high) = (a.min(b), a.max(b));
+        // fails if another thread attached the root somewhere else in the meantime
+        if parents[high as usize]
+            .compare_exchange(high, low, Relaxed, Relaxed)
+            .is_ok()
+        {
+            return;
+        }
+    }
+}
+
+/// Bits of a float, which are ordered like the float itself when compared as integers
+fn ordered_bits(f: f32) -> u32 {
+    let bits = f.to_bits();
+    if bits >> 31 == 1 {
+        !bits
+    } else {
+        bits | 1 << 31
+    }
+}
+
+/// Inverse of [`ordered_bits`](./fn.ordered_bits.html)
+fn from_ordered_bits(bits: u32) -> f32 {
+    if bits >> 31 == 1 {
+        f32::from_bits(bits & !(1 << 31))
+    } else {
+        f32::from_bits(!bits)
+    }
+}
+
+/// Floods the lakes and labels them one after another, the way it was done before it was
+/// parallelised
+#[cfg(test)]
+fn sequential_lake_map(map: &Map, rivers: &Map, targets: &FlowField, ocean: f32) -> Map {
+    let width = map.width();
+    let height = map.height();
+    let wrap = targets.is_wrapped();
+
     let mut lake_origins = Vec::new();
     let mut lakes = Map::new(width, height);
 
@@ -13,9 +321,9 @@ pub fn lake_map(map: &Map, rivers: &Map, targets: &Vec<Vec<(usize, usize)>>, oce
             let xy = (x, y);
             let z = map[xy];
 
-            if z < ocean {
+            if z <= ocean {
                 lakes[xy] = 1.0;
-            } else if xy == targets[x][y] && rivers[xy] > 100.0 {
+            } else if targets.is_sink(x, y) && rivers[xy] > 100.0 {
                 lake_origins.push(Point { x, y, z });
             }
         }
@@ -26,32 +334,41 @@ pub fn lake_map(map: &Map, rivers: &Map, targets: &Vec<Vec<(usize, usize)>>, oce
         let mut points = Vec::new();
 
         macro_rules! enq {
-            ($x:expr, $y:expr) => {{
-                let xy = ($x, $y);
-                if lakes[xy] == 0.0 {
-                    queue.push(Point {
-                        x: xy.0,
-                        y: xy.1,
-                        z: map[xy],
-                    });
-                    lakes[xy] = -1.0;
-                    points.push(xy);
+            ($xy:expr) => {{
+                if let Some(xy) = $xy {
+                    if lakes[xy] == 0.0 {
+                        queue.push(Point {
+                            x: xy.0,
+                            y: xy.1,
+                            z: map[xy],
+                        });
+                        lakes[xy] = -1.0;
+                        points.push(xy);
+                        false
+                    } else {
+                        lakes[xy] > 0.0
+                    }
+                } else {
+                    // everything outside of the map counts as ocean
+                    true
                 }
-                lakes[xy] > 0.0
             }};
         }
 
-        if enq!(origin.x, origin.y) {
+        if enq!(Some((origin.x, origin.y))) {
             continue;
         }
 
         while let Some(Point { x, y, z }) = queue.pop() {
-            if z < ocean {
+            if z <= ocean {
                 break;
             }
 
-            let (nx, ny) = targets[x][y];
-            if enq!(x - 1, y) || enq!(x + 1, y) || enq!(x, y - 1) || enq!(x, y + 1) || enq!(nx, ny)
+            if enq!(map.offset(x, y, -1, 0, wrap))
+                || enq!(map.offset(x, y, 1, 0, wrap))
+                || enq!(map.offset(x, y, 0, -1, wrap))
+                || enq!(map.offset(x, y, 0, 1, wrap))
+                || enq!(Some(targets.target(x, y)))
             {
                 break;
             }
@@ -62,46 +379,36 @@ pub fn lake_map(map: &Map, rivers: &Map, targets: &Vec<Vec<(usize, usize)>>, oce
         }
     }
 
-    let mut groups = vec![vec![0usize; height]; width];
+    let mut groups = Grid::<u32>::new(width, height);
     let mut max = vec![0.0];
     let mut area = vec![0];
 
     for x in 0..width {
         for y in 0..height {
-            if groups[x][y] == 0 && lakes[(x, y)] > 0.0 {
-                let group = max.len();
+            if groups[(x, y)] == 0 && lakes[(x, y)] > 0.0 {
+                let group = max.len() as u32;
                 max.push(ocean);
                 area.push(0);
 
                 let mut queue = Vec::new();
 
-                macro_rules! enq {
-                    ($x: expr, $y: expr) => {
-                        let (x, y) = ($x, $y);
-                        if groups[x][y] == 0 && lakes[(x, y)] > 0.0 {
-                            queue.push((x, y));
-                            groups[x][y] = group;
-                        }
-                    };
-                }
-
-                enq!(x, y);
+                queue.push((x, y));
+                groups[(x, y)] = group;
 
                 while let Some((x, y)) = queue.pop() {
-                    area[group] += 1;
-                    max[group] = max[group].max(map[(x, y)]);
+                    area[group as usize] += 1;
+                    max[group as usize] = max[group as usize].max(map[(x, y)]);
 
-                    let next = |val, limit| val + (val + 1 < limit) as usize;
-                    let prev = |val| val - (val > 0) as usize;
-
-                    enq!(prev(x), y);
-                    enq!(next(x, width), y);
-                    enq!(x, prev(y));
-                    enq!(x, next(y, height));
+                    for xy in map.neighbors4(x, y, wrap) {
+                        if groups[xy] == 0 && lakes[xy] > 0.0 {
+                            queue.push(xy);
+                            groups[xy] = group;
+                        }
+                    }
                 }
             }
 
-            let group = groups[x][y];
+            let group = groups[(x, y)] as usize;
             if area[group] > 10 {
                 lakes[(x, y)] = max[group];
             } else {
@@ -112,3 +419,148 @@ pub fn lake_map(map: &Map, rivers: &Map, targets: &Vec<Vec<(usize, usize)>>, oce
 
     lakes
 }
+
+/// Check that the parallel lake map is exactly the same as the sequential one, on seeded maps with
+/// many neighbouring pits and on the terrain with flat lakes, where lakes run into each other, on
+/// tileable maps and with different numbers of threads
+#[test]
+fn test_parallel_lake_map() {
+    use crate::{river::*, simplex::*, water_terrain::*};
+    use rand::prelude::*;
+
+    let mut maps = Vec::new();
+    for seed in 0..4 {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let (width, height) = (rng.gen_range(100..200), rng.gen_range(100..200));
+        let pits: Vec<(f32, f32, f32, f32)> = (0..40)
+            .map(|_| {
+                (
+                    rng.gen_range(0.0..width as f32),
+                    rng.gen_range(0.0..height as f32),
+                    rng.gen_range(4.0..20.0),
+                    rng.gen_range(1.0..8.0),
+                )
+            })
+            .collect();
+
+        let mut map = Map::new(width, height);
+        map.map_coords(|x, y, _| {
+            let (x, y) = (x as f32, y as f32);
+            // a plateau falling off into the ocean at the edges
+            let edge = x.min(y).min(width as f32 - x).min(height as f32 - y);
+            let plateau = 20.0 + (0.5 * edge).min(15.0);
+            let depth: f32 = pits
+                .iter()
+                .map(|&(px, py, radius, depth)| {
+                    let dist = (x - px).hypot(y - py);
+                    depth * (1.0 - dist / radius).max(0.0)
+                })
+                .sum();
+            plateau - depth
+        });
+        maps.push((map, seed % 2 == 1));
+    }
+
+    for &wrap in [false, true].iter() {
+        let map = if wrap {
+            tileable_map(300, 200, &PRESETS[0], 0)
+        } else {
+            simplex_map(300, 200)
+        };
+        let targets = find_targets(&map, 6, wrap);
+        let mut rivers = create_flow_map(&map, &targets);
+        let lakes = sequential_lake_map(&map, &rivers, &targets, 20.0);
+        rivers.map(|h| h.powf(0.45));
+        let config = TerrainConfig {
+            wrap,
+            ..TerrainConfig::default()
+        };
+        maps.push((create_heightmap(&rivers, &lakes, &config), wrap));
+    }
+
+    for (map, wrap) in maps.into_iter() {
+        let targets = find_targets(&map, 6, wrap);
+        let rivers = create_flow_map(&map, &targets);
+        let expected = sequential_lake_map(&map, &rivers, &targets, 20.0);
+
+        for &threads in [1, 3].iter() {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap();
+            let lakes = pool.install(|| lake_map(&map, &rivers, &targets, 20.0));
+
+            for x in 0..map.width() {
+                for y in 0..map.height() {
+                    assert_eq!(lakes[(x, y)], expected[(x, y)]);
+                }
+            }
+        }
+    }
+}
+
+/// Check that terrain at exactly the ocean height counts as ocean, as the terrain puts the sea at
+/// that height, also on tileable maps which have no border to find the sea through
+#[test]
+fn test_ocean_height() {
+    let (width, height) = (40, 30);
+    let mut map = Map::new(width, height);
+    map.map_coords(|x, y, _| {
+        let dist = (x as f32 - 20.0).hypot(y as f32 - 15.0);
+        20.0 + (8.0 - dist).max(0.0)
+    });
+    let rivers = Map::new(width, height);
+
+    for &wrap in [false, true].iter() {
+        let targets = find_targets(&map, 6, wrap);
+        let lakes = lake_map(&map, &rivers, &targets, 20.0);
+        assert_eq!(lakes[(0, 0)], 20.0);
+        assert_eq!(lakes[(width - 1, height - 1)], 20.0);
+        assert_eq!(lakes[(20, 15)], 0.0);
+        assert_eq!(lakes[(27, 15)], 0.0);
+    }
+}
+
+/// Check that a flood which ran over an earlier lake is cut off at its water, also if that lake
+/// was flooded again and didn't mark its cells, with the order of the floods forced
+#[test]
+fn test_resolve_floods() {
+    // two pits in the middle row, the right one spilling into the left one and that one into the
+    // ocean, surrounded by high walls
+    let row = [
+        50.0, 19.0, 25.0, 22.0, 21.0, 22.0, 28.0, 24.0, 23.0, 24.0, 35.0, 35.0, 50.0,
+    ];
+    let (width, height) = (row.len(), 5);
+    let ocean = 20.0;
+    let mut map = Map::new(width, height);
+    map.map_coords(|x, y, _| if y == 2 { row[x] } else { 50.0 });
+    // every cell is a sink, so that the floods only spread through the neighbours
+    let mut cells = Grid::<u32>::new(width, height);
+    cells.map_coords(|x, y, _| map.flatten_xy(x, y) as u32);
+    let targets = FlowField::from_targets(cells, 6, false);
+
+    let mut initial = Map::new(width, height);
+    initial.map_coords(|x, y, _| if map[(x, y)] <= ocean { 1.0 } else { 0.0 });
+    let origins = [map.flatten_xy(4, 2) as u32, map.flatten_xy(8, 2) as u32];
+
+    let mut expected = initial.clone();
+    for &origin in origins.iter() {
+        for point in flood(&map, &targets, &expected, ocean, origin as usize, None) {
+            expected[map.unflatten(point as usize)] = 1.0;
+        }
+    }
+    assert_eq!(expected[(10, 2)], 0.0);
+
+    // the right lake ran over the left one before that one got to any cell, so the left one gave
+    // up at once and is flooded again
+    let owners = Grid::<AtomicU32>::new(width, height);
+    let right = flood(&map, &targets, &initial, ocean, origins[1] as usize, None);
+    for &point in right.iter() {
+        owners[map.unflatten(point as usize)].store(rank(2, 1), Relaxed);
+    }
+    let floods = vec![vec![origins[0]], right];
+
+    let mut lakes = initial.clone();
+    resolve_floods(&map, &targets, &mut lakes, ocean, &origins, &owners, floods);
+    assert_eq!(lakes.values(), expected.values());
+}
@@ -0,0 +1,169 @@
+use crate::{geometry::*, map::*};
+use cgmath::prelude::InnerSpace;
+use std::f32::consts::*;
+
+/// How much the step size of the horizon ray marching grows with the distance.
+///
+/// Far away samples only matter for high terrain, so they can be spaced wider apart.
+const SHADOW_STEP_GROWTH: f32 = 0.05;
+
+/// Position of the sun in the sky, used to light the terrain
+#[derive(Copy, Clone, Debug)]
+pub struct Sun {
+    /// direction the light comes from in radians, measured from the +x towards the +y axis
+    pub azimuth: f32,
+    /// angle of the sun above the horizon in radians
+    pub altitude: f32,
+    /// angular radius of the sun disk in radians, determines how soft the shadows are
+    pub radius: f32,
+}
+
+impl Sun {
+    /// Unit vector pointing from the terrain towards the sun
+    pub fn direction(&self) -> Vector {
+        let (sin_alt, cos_alt) = self.altitude.sin_cos();
+        let (sin_az, cos_az) = self.azimuth.sin_cos();
+        Vector::new(cos_alt * cos_az, cos_alt * sin_az, sin_alt)
+    }
+}
+
+impl Default for Sun {
+    /// A low sun in the -x -y direction
+    fn default() -> Self {
+        Self {
+            azimuth: 1.25 * PI,
+            altitude: 0.14,
+            radius: 0.02,
+        }
+    }
+}
+
+/// Calculates how much each cell of the map is lit by the sun, ignoring shadows.
+///
+/// The result is the cosine between the surface normal and the sun direction, clamped to `0..=1`.
+pub fn hillshade(map: &Map, sun: &Sun) -> Map {
+    let light = sun.direction();
+    let mut shade = Map::new(map.width(), map.height());
+    shade.map_coords(|x, y, _| map.normal(x, y).dot(light).max(0.0));
+    shade
+}
+
+/// Calculates a multi-directional hillshade.
+///
+/// The terrain is lit from four azimuths spread over 135 degrees around the sun's azimuth. Each
+/// slope is mostly lit from the azimuths that cross it at an angle, which keeps the details of
+/// slopes facing directly towards or away from the sun visible.
+pub fn multi_directional_hillshade(map: &Map, sun: &Sun) -> Map {
+    let azimuths = [-0.5 * PI, -0.25 * PI, 0.0, 0.25 * PI].map(|da| sun.azimuth + da);
+    let lights = azimuths.map(|azimuth| Sun { azimuth, ..*sun }.direction());
+
+    let mut shade = Map::new(map.width(), map.height());
+    shade.map_coords(|x, y, _| {
+        let normal = map.normal(x, y);
+        let aspect = normal.y.atan2(normal.x);
+
+        // the weights of four azimuths 45 degrees apart always sum up to 2
+        let mut light = 0.0;
+        for (azimuth, dir) in azimuths.iter().zip(lights.iter()) {
+            let weight = (aspect - azimuth).sin().powi(2);
+            light += weight * normal.dot(*dir).max(0.0);
+        }
+        0.5 * light
+    });
+    shade
+}
+
+/// Calculates how much of the sun disk is visible from each cell of the map.
+///
+/// A ray is marched from each cell towards the sun to find the angle of the horizon in that
+/// direction. The sun is fully visible if it's more than its radius above the horizon and fully
+/// hidden if it's more than its radius below it, with a soft transition in between.
+pub fn shadow_map(map: &Map, sun: &Sun) -> Map {
+    let (_, peak) = map.minmax();
+
+    let (dy, dx) = sun.azimuth.sin_cos();
+    let lowest = (sun.altitude - sun.radius).max(0.0).tan();
+    let highest = (sun.altitude + sun.radius).tan();
+    let max_dist = (map.width() as f32).hypot(map.height() as f32);
+
+    let mut shadow = Map::new(map.width(), map.height());
+    shadow.map_coords(|x, y, _| {
+        // nothing further away than this can be high enough to hide the sun
+        let reach = (peak - map[(x, y)]) / lowest;
+        let horizon = horizon_tangent(map, x, y, (dx, dy), reach.min(max_dist), highest).atan();
+
+        if sun.radius > 0.0 {
+            ((sun.altitude - horizon) / (2.0 * sun.radius) + 0.5).clamp(0.0, 1.0)
+        } else if sun.altitude > horizon {
+            1.0
+        } else {
+            0.0
+        }
+    });
+    shadow
+}
+
+/// Finds the tangent of the horizon angle seen from `(x, y)` in the direction `(dx, dy)`.
+///
+/// Only terrain up to `max_dist` away is considered. The search stops early once the horizon
+/// reaches the tangent `limit`. Returns `f32::MIN` if there is no terrain in that direction.
+pub fn horizon_tangent(
+    map: &Map,
+    x: usize,
+    y: usize,
+    (dx, dy): (f32, f32),
+    max_dist: f32,
+    limit: f32,
+) -> f32 {
+    let (width, height) = (map.width() as f32, map.height() as f32);
+    let z = map[(x, y)];
+    let (x, y) = (x as f32, y as f32);
+
+    let mut horizon = f32::MIN;
+    let mut dist = 1.0;
+
+    while dist <= max_dist && horizon < limit {
+        let (px, py) = (x + dx * dist, y + dy * dist);
+        if px < 0.0 || py < 0.0 || px > width - 1.0 || py > height - 1.0 {
+            break;
+        }
+
+        horizon = horizon.max((map.sample_bilinear(px, py) - z) / dist);
+        dist += 1.0 + dist * SHADOW_STEP_GROWTH;
+    }
+
+    horizon
+}
+
+/// Check that a raised block casts its shadow away from the sun, whatever direction the light
+/// comes from
+#[test]
+fn test_shadow_direction() {
+    let size = 64;
+    let mut map = Map::new(size, size);
+    for x in 31..=33 {
+        for y in 31..=33 {
+            map[(x, y)] = 20.0;
+        }
+    }
+
+    for &azimuth in [0.0, 0.5 * PI, 0.75 * PI, 1.6 * PI].iter() {
+        let sun = Sun {
+            azimuth,
+            altitude: 0.5,
+            radius: 0.0,
+        };
+        let shadow = shadow_map(&map, &sun);
+
+        let behind = |dist: f32| {
+            let x = 32.0 - azimuth.cos() * dist;
+            let y = 32.0 - azimuth.sin() * dist;
+            shadow[(x.round() as usize, y.round() as usize)]
+        };
+        assert_eq!(behind(5.0), 0.0);
+        assert_eq!(behind(-5.0), 1.0);
+    }
+
+    let flat = hillshade(&Map::new(8, 8), &Sun::default());
+    assert!((flat[(4, 4)] - Sun::default().altitude.sin()).abs() < 1e-6);
+}
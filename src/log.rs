@@ -22,7 +22,7 @@ impl Logger {
         }
         result
     }
-    pub fn finalize(self) {
+    pub fn finalize(&self) {
         println!(
             "{: <25}[{:.3} s]\n",
             "Total",
@@ -1,51 +1,248 @@
+#![allow(clippy::needless_range_loop)]
+
 use std::fs::File;
 use std::io::*;
+use std::sync::Arc;
 
+mod analysis;
+mod buffer;
+mod combine;
+mod contour;
 mod draw;
 mod flow;
 mod geometry;
+mod grid;
 mod lake;
+mod light;
 mod log;
 mod map;
 mod obj;
+mod pipeline;
+mod resample;
+mod ridge;
 mod river;
+mod scatter;
+mod serialize;
+mod settlement;
 mod simplex;
+mod slope;
+mod splat;
+mod valley;
 mod vis;
 mod water_terrain;
+mod zlib;
 
 fn main() -> std::io::Result<()> {
     let export_wetmap = false;
     let export_heightmap = true;
     let export_obj = false;
     let export_rgb = true;
+    let export_analysis = false;
+    let export_normal_map = false;
+    let export_splat = false;
+    let scatter_objects = false;
+    let generate_roads = false;
+    let raise_ridges = false;
+    let carve_valleys = false;
+    // tileable maps wrap around the edges instead of being surrounded by ocean
+    let tileable = false;
+    // large maps are stored in memory-mapped files in the working directory, so that maps which
+    // don't fit into memory can be generated
+    let out_of_core = false;
 
-    let size = 2000;
+    let width = 2000;
+    let height = 2000;
     let water_range = 6;
     let ocean_height = 20.0;
+    // file marking where the terrain is flatter or steeper, a grey png image or a saved map, see
+    // `water_terrain::load_slope_map`
+    let slope_map: Option<&str> = None;
+    // selects the island, the same seed always gives the same one
+    let seed = 0;
+
+    let name = std::env::args().nth(1);
+    let preset = match name.as_deref() {
+        None => simplex::PRESETS[0],
+        Some(name) => simplex::preset(name).ok_or_else(|| {
+            let names: Vec<_> = simplex::PRESETS.iter().map(|p| p.name).collect();
+            let message = format!("unknown preset {}, use one of {}", name, names.join(", "));
+            Error::new(ErrorKind::InvalidInput, message)
+        })?,
+    };
+
+    // slope of the terrain along the rivers, `power-law` carves sharper canyons and `logistic`
+    // broader valleys, `curve` takes any shape, see `slope::parse_model`
+    let slope_model: Arc<dyn slope::SlopeModel> = match std::env::args().nth(2) {
+        None => Arc::new(slope::InverseSlope::default()),
+        Some(description) => slope::parse_model(&description)
+            .map_err(|message| Error::new(ErrorKind::InvalidInput, message))?,
+    };
 
     println!("\n-- Island Generator --\n");
-    println!("Generating a {}x{} island.", size, size);
+    println!(
+        "Generating a {}x{} island: {}.",
+        width, height, preset.description
+    );
+
+    if out_of_core {
+        buffer::spill_to_disk(".", 64 << 20);
+    }
 
     let logger = log::Logger::new();
 
-    let map = logger.do_task("Generating Simplex Map", || {
-        simplex::simplex_map(size, size)
-    });
-    let targets = logger.do_task("Finding Flow Targets", || {
-        flow::find_targets(&map, water_range)
-    });
-    let mut river_map = logger.do_task("Generating River Map", || {
-        river::create_flow_map(&map, &targets)
-    });
-    let lake_map = logger.do_task("Generating Lake Map", || {
-        lake::lake_map(&map, &river_map, &targets, ocean_height)
-    });
-
-    logger.do_task("Adjusting River Map", || river_map.map(|h| h.powf(0.45)));
-
-    let water_terrain = logger.do_task("Generating Terrain", || {
-        water_terrain::create_heightmap(&river_map, &lake_map)
-    });
+    // the output of every stage is cached in this directory, keyed by its parameters and inputs,
+    // so that only the stages affected by a change run again, for example only the renderer when
+    // changing its style
+    let cache_stages = false;
+    let cache = if cache_stages {
+        Some(std::path::PathBuf::from("cache"))
+    } else {
+        None
+    };
+    let pipeline = pipeline::Pipeline::new(&logger, cache)?;
+
+    let ridge_config = ridge::RidgeConfig {
+        seed,
+        ..ridge::RidgeConfig::default()
+    };
+    let valley_config = valley::ValleyConfig {
+        attractors: width * height / 400,
+        seed,
+        ..valley::ValleyConfig::default()
+    };
+    let noise = pipeline
+        .stage("noise", "Generating Simplex Map")
+        .param(&(width, height, tileable, seed))
+        // the shape is a function, which is identified by the name of the preset
+        .param(&preset.name)
+        .param(&(
+            preset.noise_amplitude,
+            preset.noise_base,
+            preset.noise_scale,
+        ))
+        .param(&Some(&ridge_config).filter(|_| raise_ridges))
+        // valleys are only carved above the ocean
+        .param(&Some((&valley_config, ocean_height)).filter(|_| carve_valleys))
+        .build(|| {
+            let mut map = if tileable {
+                simplex::tileable_map(width, height, &preset, seed)
+            } else {
+                simplex::preset_map(width, height, &preset, seed)
+            };
+            if raise_ridges {
+                map = ridge::raise_ridges(&map, &ridge_config);
+            }
+            if carve_valleys {
+                let valleys = valley::generate_valleys(&map, ocean_height, &valley_config);
+                valley::carve_valleys(&mut map, &valleys, ocean_height, valley_config.depth);
+            }
+            Ok(map)
+        });
+    let targets = pipeline
+        .stage("targets", "Finding Flow Targets")
+        .param(&(water_range, tileable))
+        .input(&noise)
+        .build(|| Ok(flow::find_targets(&*noise.get()?, water_range, tileable)));
+    let rivers = pipeline
+        .stage("rivers", "Generating River Map")
+        .input(&noise)
+        .input(&targets)
+        .build(|| Ok(river::create_flow_map(&*noise.get()?, &*targets.get()?)));
+    let lakes = pipeline
+        .stage("lakes", "Generating Lake Map")
+        .param(&ocean_height)
+        .input(&noise)
+        .input(&rivers)
+        .input(&targets)
+        .build(|| {
+            let lakes = lake::lake_map(
+                &*noise.get()?,
+                &*rivers.get()?,
+                &*targets.get()?,
+                ocean_height,
+            );
+            // free the memory of the targets for the next stages
+            targets.release();
+            Ok(lakes)
+        });
+    let adjusted_rivers = pipeline
+        .stage("adjusted_rivers", "Adjusting River Map")
+        .input(&rivers)
+        .build(|| {
+            let mut rivers = (*rivers.get()?).clone();
+            rivers.map(|h| h.powf(0.45));
+            Ok(rivers)
+        });
+
+    // the slope map is identified by its path and when it was last changed
+    let slope_modified = slope_map
+        .map(|path| std::fs::metadata(path)?.modified())
+        .transpose()?;
+    let terrain = pipeline
+        .stage("terrain", "Generating Terrain")
+        .param(&(&slope_model, tileable))
+        .param(&(slope_map, slope_modified))
+        .input(&adjusted_rivers)
+        .input(&lakes)
+        .build(|| {
+            // the lakes and the adjusted rivers are ready at this point and hold all that is
+            // needed of the noise and the rivers, so their memory is freed for the next stages
+            noise.release();
+            rivers.release();
+            let slope = slope_map
+                .map(|path| water_terrain::load_slope_map(path, width, height))
+                .transpose()?;
+            let config = water_terrain::TerrainConfig {
+                slope_model: slope_model.clone(),
+                slope,
+                wrap: tileable,
+            };
+            let rivers = adjusted_rivers.get()?;
+            Ok(water_terrain::create_heightmap(
+                &rivers,
+                &*lakes.get()?,
+                &config,
+            ))
+        });
+
+    // rivers and lakes of the final terrain, shared by the renderer and the placement rules
+    let terrain_targets = pipeline
+        .stage("terrain_targets", "Refinding Flow Targets")
+        .param(&(water_range, tileable))
+        .input(&terrain)
+        .build(|| Ok(flow::find_targets(&*terrain.get()?, water_range, tileable)));
+    let terrain_rivers = pipeline
+        .stage("terrain_rivers", "Regenerating River Map")
+        .input(&terrain)
+        .input(&terrain_targets)
+        .build(|| {
+            Ok(river::create_flow_map(
+                &*terrain.get()?,
+                &*terrain_targets.get()?,
+            ))
+        });
+    let terrain_lakes = pipeline
+        .stage("terrain_lakes", "Regenerating Lake Map")
+        .param(&ocean_height)
+        .input(&terrain)
+        .input(&terrain_rivers)
+        .input(&terrain_targets)
+        .input(&lakes)
+        .build(|| {
+            let terrain_lakes = lake::lake_map(
+                &*terrain.get()?,
+                &*terrain_rivers.get()?,
+                &*terrain_targets.get()?,
+                ocean_height,
+            );
+            // lakes are flat in the terrain, so nothing flows into the ones high up anymore and
+            // they have to be taken over from the original lake map
+            Ok(terrain_lakes.max(&*lakes.get()?))
+        });
+
+    let water_terrain = terrain.get()?;
+    let terrain_river = terrain_rivers.get()?;
+    let terrain_lake = terrain_lakes.get()?;
 
     if export_obj {
         logger.do_task("Exporting Terrain OBJ", || {
@@ -63,7 +260,70 @@ fn main() -> std::io::Result<()> {
         })?;
     }
 
+    if export_normal_map {
+        logger.do_task("Exporting Normal Map", || {
+            let normal_file = File::create("normal.png")?;
+            let convention = analysis::NormalConvention::OpenGl;
+            water_terrain.export_normal_map(normal_file, 1.0, convention)
+        })?;
+    }
+
+    let (_, peak) = water_terrain.minmax();
+    let rule_input = splat::SplatInput {
+        terrain: &water_terrain,
+        river: &terrain_river,
+        lake: &terrain_lake,
+        bands: vis::Bands::new(ocean_height + 1.0, peak),
+        river_level: 100.0,
+    };
+
+    if export_splat {
+        logger.do_task("Exporting Splat Maps", || {
+            let weights = splat::splat_weights(&rule_input, &splat::default_materials());
+            splat::export_splat_maps(&weights, |layer| {
+                File::create(format!("splat{}.png", layer))
+            })
+        })?;
+    }
+
+    let mut overlay = vis::Overlay::default();
+
+    if scatter_objects {
+        logger.do_task("Scattering Objects", || -> Result<()> {
+            let types = scatter::default_object_types();
+            let instances = scatter::scatter(&rule_input, &types, seed);
+            let csv_file = BufWriter::new(File::create("objects.csv")?);
+            scatter::write_csv(&instances, &types, csv_file)?;
+            let json_file = BufWriter::new(File::create("objects.json")?);
+            scatter::write_json(&instances, &types, json_file)?;
+            overlay.markers = scatter::markers(&instances, &types);
+            Ok(())
+        })?;
+    }
+
+    if generate_roads {
+        logger.do_task("Generating Roads", || -> Result<()> {
+            let towns = settlement::place_towns(&rule_input, 8, 0.1 * width.min(height) as f32);
+            let costs = settlement::RoadCosts::default();
+            let roads = settlement::build_roads(&rule_input, &towns, &costs);
+
+            let roads_file = File::create("roads.obj")?;
+            let mut buf_writer = BufWriter::new(roads_file);
+            let mut writer = obj::ObjWriter::new(&mut buf_writer)?;
+            settlement::export_roads(&roads, &water_terrain, &mut writer)?;
+
+            let road_map = settlement::road_map(&roads, width, height);
+            road_map.export_image(File::create("roads.png")?)?;
+
+            let network = settlement::overlay(&towns, &roads);
+            overlay.lines.extend(network.lines);
+            overlay.markers.extend(network.markers);
+            Ok(())
+        })?;
+    }
+
     if export_wetmap {
+        let (river_map, lake_map) = (adjusted_rivers.get()?, lakes.get()?);
         logger.do_task("Exporting River/Lake", || {
             let river_file = File::create("river.png")?;
             river_map.export_image(river_file)?;
@@ -72,23 +332,38 @@ fn main() -> std::io::Result<()> {
         })?;
     }
 
+    if export_analysis {
+        let terrain_targets = terrain_targets.get()?;
+        logger.do_task("Exporting Analysis Maps", || {
+            let ao = water_terrain.ambient_occlusion(16, 32.0);
+            ao.export_image_range(File::create("ao.png")?, 0.0, 1.0)?;
+
+            let curvature = water_terrain.curvature();
+            let (min, max) = curvature.minmax();
+            let extent = max.max(-min);
+            curvature.export_image_range(File::create("curvature.png")?, -extent, extent)?;
+
+            let slope = water_terrain.slope();
+            slope.export_image_range(File::create("slope.png")?, 0.0, 90.0)?;
+            let aspect = water_terrain.aspect();
+            aspect.export_image_range(File::create("aspect.png")?, 0.0, 360.0)?;
+
+            water_terrain.export_flow_direction(&terrain_targets, File::create("flow.png")?)
+        })?;
+    }
+
     if export_rgb {
-        //let targets = logger.do_task("Refinding Flow Targets", || {
-        //    flow::find_targets(&map, water_range)
-        //});
-        //let mut river_map = logger.do_task("Regenerating River Map", || {
-        //    river::create_flow_map(&map, &targets)
-        //});
-        //let lake_map = logger.do_task("Regenerating Lake Map", || {
-        //    lake::lake_map(&map, &river_map, &targets, ocean_height)
-        //});
         logger.do_task("Creating Visualization", || {
-            let map = &water_terrain;
-            let flow = flow::find_targets(map, water_range);
-            let river = river::create_flow_map(map, &flow);
-            let lake = lake::lake_map(map, &river, &flow, ocean_height);
             let file = File::create("vis.png")?;
-            vis::visualize(&map, &river, &lake, ocean_height + 1.0, file)
+            let style = vis::Style::default();
+            vis::visualize(
+                &water_terrain,
+                &terrain_lake,
+                ocean_height + 1.0,
+                &style,
+                &overlay,
+                file,
+            )
         })?;
     }
 
@@ -1,73 +1,46 @@
-use crate::{geometry::*, obj::*};
+pub use crate::grid::*;
+use crate::{geometry::*, obj::*, zlib::*};
+use cgmath::prelude::InnerSpace;
 use rayon::prelude::*;
 use std::cmp::*;
-use std::io::{Error, ErrorKind, Result, Write};
+use std::io::{Error, ErrorKind, Read, Result, Write};
 use std::ops::*;
 
-/// Generic matrix type with a fixed width and heigth that holds floats.
+/// Grid of floats.
 ///
 /// It can be used to store heightmaps, wetness or other kinds of maps of a terrain.
-#[derive(Clone)]
-pub struct Map {
-    width: usize,
-    height: usize,
-    buffer: Vec<f32>,
-}
+pub type Map = Grid<f32>;
 
 impl Map {
-    pub fn new(width: usize, height: usize) -> Self {
-        Self {
-            width,
-            height,
-            buffer: vec![0.0; width * height],
-        }
-    }
-
-    pub fn width(&self) -> usize {
-        self.width
-    }
-
-    pub fn height(&self) -> usize {
-        self.height
-    }
-
-    /// maps each entry of the map to a new value
-    pub fn map<F>(&mut self, fun: F)
-    where
-        F: Send + Sync + Fn(f32) -> f32,
-    {
-        self.map_coords(|_, _, h| fun(h))
-    }
-
-    /// maps each entry of the map, together with its coordinates to a new value
-    pub fn map_coords<F>(&mut self, fun: F)
-    where
-        F: Send + Sync + Fn(usize, usize, f32) -> f32,
-    {
-        self.buffer
-            .par_chunks_mut(self.width)
-            .enumerate()
-            .for_each(|(y, chunk)| {
-                for (x, h) in chunk.iter_mut().enumerate() {
-                    *h = fun(x, y, *h);
-                }
-            });
-    }
-
     /// Returns the minimum and maximum value of the map
     pub fn minmax(&self) -> (f32, f32) {
-        let mut min = std::f32::MAX;
-        let mut max = std::f32::MIN;
-        for &h in self.buffer.iter() {
+        let mut min = f32::MAX;
+        let mut max = f32::MIN;
+        for &h in self.values().iter() {
             min = min.min(h);
             max = max.max(h);
         }
         (min, max)
     }
 
-    #[inline(always)]
-    pub fn flatten_xy(&self, x: usize, y: usize) -> usize {
-        x + y * self.width
+    /// Returns the height gradient `(dz/dx, dz/dy)` at `(x, y)`.
+    ///
+    /// It uses Horn's method, a Sobel-like 3x3 kernel which is much less noisy than the difference
+    /// of two neighbors. Border cells reuse the closest values inside the map.
+    pub fn gradient(&self, x: usize, y: usize) -> (f32, f32) {
+        let (x, y) = (x as isize, y as isize);
+        let z = |dx, dy| self.get_clamped(x + dx, y + dy);
+
+        let dzdx = (z(1, -1) + 2.0 * z(1, 0) + z(1, 1)) - (z(-1, -1) + 2.0 * z(-1, 0) + z(-1, 1));
+        let dzdy = (z(-1, 1) + 2.0 * z(0, 1) + z(1, 1)) - (z(-1, -1) + 2.0 * z(0, -1) + z(1, -1));
+
+        (dzdx / 8.0, dzdy / 8.0)
+    }
+
+    /// Returns the unit surface normal at `(x, y)`, pointing upwards (+z)
+    pub fn normal(&self, x: usize, y: usize) -> Vector {
+        let (dzdx, dzdy) = self.gradient(x, y);
+        Vector::new(-dzdx, -dzdy, 1.0).normalize()
     }
 }
 
@@ -94,6 +67,46 @@ impl Map {
         Ok(())
     }
 
+    /// exports the heightmap as obj mesh with `width` by `height` vertices, which are
+    /// interpolated between the cells so that the mesh covers the same area at any resolution.
+    /// The mesh needs at least two vertices in each direction.
+    #[allow(unused)]
+    pub fn export_mesh_sampled<W: Write>(
+        &self,
+        obj: &mut ObjWriter<W>,
+        width: usize,
+        height: usize,
+    ) -> Result<()> {
+        assert!(
+            width >= 2 && height >= 2,
+            "mesh of {}x{} vertices has no area",
+            width,
+            height
+        );
+        let sx = (self.width() - 1) as f32 / (width - 1) as f32;
+        let sy = (self.height() - 1) as f32 / (height - 1) as f32;
+        let vertex = |x, y| {
+            let (x, y) = (x as f32 * sx, y as f32 * sy);
+            Vector::new(x as _, y as _, self.sample_bilinear(x, y))
+        };
+
+        for x in 0..width - 1 {
+            for y in 0..height - 1 {
+                obj.triangle(&Triangle([
+                    vertex(x, y),
+                    vertex(x + 1, y),
+                    vertex(x, y + 1),
+                ]))?;
+                obj.triangle(&Triangle([
+                    vertex(x + 1, y),
+                    vertex(x + 1, y + 1),
+                    vertex(x, y + 1),
+                ]))?;
+            }
+        }
+        Ok(())
+    }
+
     /// exports the heightmap as cube mesh
     #[allow(unused)]
     pub fn export_cube_mesh<W: Write>(&self, obj: &mut ObjWriter<W>) -> Result<()> {
@@ -132,58 +145,159 @@ impl Map {
     /// exports the heightmap as png image
     #[allow(unused)]
     pub fn export_image<W: Write>(&self, writer: W) -> Result<()> {
-        let mut encoder = png::Encoder::new(writer, self.width() as _, self.height() as _);
-        encoder.set_color(png::ColorType::Grayscale);
-        encoder.set_depth(png::BitDepth::Eight);
-        let mut writer = encoder.write_header()?;
+        let (min, max) = self.minmax();
+        self.export_image_range(writer, min, max)
+    }
+
+    /// exports the map as png image, mapping the values from `min..=max` to black..white
+    #[allow(unused)]
+    pub fn export_image_range<W: Write>(&self, writer: W, min: f32, max: f32) -> Result<()> {
+        write_png_rows(
+            writer,
+            self.width(),
+            self.height(),
+            png::ColorType::Grayscale,
+            |y, row| {
+                for (x, pixel) in row.iter_mut().enumerate() {
+                    let val01 = ((self[(x, y)] - min) / (max - min)).clamp(0.0, 1.0);
+                    *pixel = (255.0 * val01) as _;
+                }
+            },
+        )
+    }
 
-        let mut buffer = vec![0u8; self.width() * self.height()];
+    /// imports a png image, mapping black..white to `min..=max` like
+    /// [`export_image_range`](#method.export_image_range).
+    ///
+    /// Images with colors are read from their first channel. The image is decoded row by row, so
+    /// that large images are never in memory twice.
+    pub fn import_image_range<R: Read>(reader: R, min: f32, max: f32) -> Result<Map> {
+        let mut decoder = png::Decoder::new(reader);
+        // palettes and bit depths below 8 are expanded to 8 bits, and 16 bits are kept
+        decoder.set_transformations(png::Transformations::EXPAND);
+        // only single rows are held in memory
+        decoder.set_limits(png::Limits { bytes: usize::MAX });
+        let (info, mut reader) = decoder.read_info()?;
+        if reader.info().interlaced {
+            let message = "interlaced png images aren't supported";
+            return Err(Error::new(ErrorKind::InvalidData, message));
+        }
 
-        let (min, max) = self.minmax();
+        let wide = info.bit_depth == png::BitDepth::Sixteen;
+        let pixel_len = info.color_type.samples() * if wide { 2 } else { 1 };
+        let white = if wide { 65535.0 } else { 255.0 };
 
-        for x in 0..self.width() {
-            for y in 0..self.height() {
-                let idx = y * self.width() + x;
-                let val = self[(x, y)];
-                let val01 = (val - min) / (max - min);
-                buffer[idx] = (255.0 * val01) as _;
+        let mut map = Map::new(info.width as usize, info.height as usize);
+        for y in 0..map.height() {
+            let row = reader
+                .next_row()?
+                .ok_or_else(|| Error::new(ErrorKind::UnexpectedEof, "png image ends early"))?;
+            for (x, pixel) in row.chunks_exact(pixel_len).enumerate() {
+                let value = if wide {
+                    u16::from_be_bytes([pixel[0], pixel[1]]) as f32
+                } else {
+                    pixel[0] as f32
+                };
+                map[(x, y)] = min + (max - min) * value / white;
             }
         }
-
-        writer
-            .write_image_data(&buffer)
-            .map_err(|_| Error::new(ErrorKind::Other, "PNG encoding error"))
+        Ok(map)
     }
 }
 
-impl Index<(usize, usize)> for Map {
-    type Output = f32;
+/// Number of bytes of the image kept in memory at once while encoding a png
+const PNG_BAND_BYTES: usize = 1 << 22;
+/// Size of the compressed chunks of image data in a png
+const PNG_CHUNK_BYTES: usize = 1 << 20;
 
-    fn index(&self, (x, y): (usize, usize)) -> &Self::Output {
-        &self.buffer[self.flatten_xy(x, y)]
+/// Encodes an 8 bit per channel image with the given color type as png.
+///
+/// The image is encoded in bands of rows, so that large images never have to be in memory at
+/// once. `fill` is called with the rows of each band, from top to bottom, and has to write their
+/// pixels into the buffer.
+pub fn write_png<W: Write>(
+    writer: W,
+    width: usize,
+    height: usize,
+    color: png::ColorType,
+    mut fill: impl FnMut(Range<usize>, &mut [u8]),
+) -> Result<()> {
+    let mut encoder = png::Encoder::new(writer, width as _, height as _);
+    encoder.set_color(color);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+
+    let pixel_len = color.samples();
+    let row_len = pixel_len * width;
+    let band_rows = (PNG_BAND_BYTES / row_len.max(1)).max(1);
+    let mut band = vec![0u8; band_rows * row_len];
+    // filter type byte followed by the filtered row
+    let mut filtered = vec![0u8; row_len + 1];
+
+    let chunks = ChunkWriter {
+        png: &mut writer,
+        data: Vec::with_capacity(PNG_CHUNK_BYTES),
+    };
+    let mut zlib = ZlibWriter::new(chunks, 1);
+
+    for y in (0..height).step_by(band_rows) {
+        let rows = y..(y + band_rows).min(height);
+        let band = &mut band[..rows.len() * row_len];
+        fill(rows, band);
+
+        for row in band.chunks(row_len) {
+            // the sub filter stores each byte as the difference to the byte of the pixel to the
+            // left, which compresses smooth images a lot better
+            filtered[0] = png::FilterType::Sub as u8;
+            for i in 0..row_len {
+                let left = if i < pixel_len { 0 } else { row[i - pixel_len] };
+                filtered[i + 1] = row[i].wrapping_sub(left);
+            }
+            zlib.write_all(&filtered)?;
+        }
     }
+
+    zlib.finish()?.flush()
 }
 
-impl IndexMut<(usize, usize)> for Map {
-    fn index_mut(&mut self, (x, y): (usize, usize)) -> &mut Self::Output {
-        let idx = self.flatten_xy(x, y);
-        &mut self.buffer[idx]
-    }
+/// Same as [`write_png`](./fn.write_png.html), but calculating the pixels of each row of a band
+/// in parallel with `row`
+pub fn write_png_rows<W: Write>(
+    writer: W,
+    width: usize,
+    height: usize,
+    color: png::ColorType,
+    row: impl Sync + Fn(usize, &mut [u8]),
+) -> Result<()> {
+    let row_len = color.samples() * width;
+    write_png(writer, width, height, color, |rows, band| {
+        band.par_chunks_mut(row_len.max(1))
+            .zip(rows)
+            .for_each(|(pixels, y)| row(y, pixels));
+    })
 }
 
-impl Add<&Map> for Map {
-    type Output = Self;
-    fn add(mut self, other: &Self) -> Self {
-        self.map_coords(|x, y, h| h + other[(x, y)]);
-        self
-    }
+/// Splits the compressed image data of a png into chunks
+struct ChunkWriter<'a, W: Write> {
+    png: &'a mut png::Writer<W>,
+    data: Vec<u8>,
 }
 
-impl Mul<f32> for Map {
-    type Output = Self;
-    fn mul(mut self, fac: f32) -> Self {
-        self.map(|h| h * fac);
-        self
+impl<'a, W: Write> Write for ChunkWriter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.data.extend_from_slice(buf);
+        if self.data.len() >= PNG_CHUNK_BYTES {
+            self.flush()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if !self.data.is_empty() {
+            self.png.write_chunk(png::chunk::IDAT, &self.data)?;
+            self.data.clear();
+        }
+        Ok(())
     }
 }
 
@@ -197,7 +311,7 @@ pub struct Point {
 
 impl PartialOrd for Point {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        other.z.partial_cmp(&self.z)
+        Some(self.cmp(other))
     }
 }
 
@@ -205,6 +319,50 @@ impl Eq for Point {}
 
 impl Ord for Point {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.partial_cmp(other).unwrap()
+        other.z.partial_cmp(&self.z).unwrap()
+    }
+}
+
+/// Check that images spanning multiple bands and chunks are encoded without loss
+#[test]
+fn test_write_png_bands() {
+    use rand::prelude::*;
+
+    let (width, height) = (1500, 1000);
+    let mut rng = StdRng::seed_from_u64(0);
+    let image: Vec<u8> = (0..3 * width * height).map(|_| rng.gen()).collect();
+
+    let mut encoded = Vec::new();
+    write_png(
+        &mut encoded,
+        width,
+        height,
+        png::ColorType::RGB,
+        |rows, band| {
+            band.copy_from_slice(&image[3 * width * rows.start..3 * width * rows.end]);
+        },
+    )
+    .unwrap();
+
+    let (info, mut reader) = png::Decoder::new(&encoded[..]).read_info().unwrap();
+    let mut decoded = vec![0u8; info.buffer_size()];
+    reader.next_frame(&mut decoded).unwrap();
+    assert_eq!(decoded, image);
+}
+
+/// Check that an exported image is imported again with the values rounded to its 256 shades
+#[test]
+fn test_image_round_trip() {
+    let (width, height) = (300, 20);
+    let mut map = Map::new(width, height);
+    map.map_coords(|x, y, _| (x + y) as f32 / (width + height - 2) as f32);
+
+    let mut image = Vec::new();
+    map.export_image_range(&mut image, 0.0, 1.0).unwrap();
+    let imported = Map::import_image_range(&image[..], 0.0, 2.0).unwrap();
+    assert_eq!((imported.width(), imported.height()), (width, height));
+    for (&value, &imported) in map.values().iter().zip(imported.values()) {
+        assert!((2.0 * value - imported).abs() <= 2.0 / 255.0);
     }
+    assert!(Map::import_image_range(&image[..10], 0.0, 1.0).is_err());
 }
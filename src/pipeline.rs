@@ -0,0 +1,293 @@
+use crate::{flow::*, log::*, map::*, serialize::FORMAT_VERSION};
+use std::cell::RefCell;
+use std::fmt::Debug;
+use std::io::Result;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// Version of the stages, which has to be increased whenever one of them changes the way it
+/// generates its output, so that outputs cached by earlier versions aren't used anymore
+const STAGE_VERSION: u32 = 1;
+
+/// Hash of everything the output of a stage depends on, which stays the same across runs
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Key(u64);
+
+impl Key {
+    fn new(name: &str) -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+            .hash(&STAGE_VERSION.to_le_bytes())
+            .hash(&FORMAT_VERSION.to_le_bytes())
+            .hash(name.as_bytes())
+    }
+
+    /// FNV-1a hash of the bytes, preceded by their length so that consecutive values can't be
+    /// confused with each other
+    fn hash(self, bytes: &[u8]) -> Self {
+        let len = (bytes.len() as u64).to_le_bytes();
+        let hash = len.iter().chain(bytes).fold(self.0, |hash, &byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+        });
+        Self(hash)
+    }
+}
+
+/// Output of a stage, which can be cached in a file
+pub trait Cacheable: Sized {
+    /// extension of the cache files
+    const EXTENSION: &'static str;
+    fn write_cache(&self, path: &Path) -> Result<()>;
+    fn read_cache(path: &Path) -> Result<Self>;
+}
+
+impl Cacheable for Map {
+    const EXTENSION: &'static str = "grid";
+
+    fn write_cache(&self, path: &Path) -> Result<()> {
+        self.save(path, false)
+    }
+
+    fn read_cache(path: &Path) -> Result<Self> {
+        Map::load(path)
+    }
+}
+
+impl Cacheable for FlowField {
+    const EXTENSION: &'static str = "flow";
+
+    fn write_cache(&self, path: &Path) -> Result<()> {
+        self.save(path, true)
+    }
+
+    fn read_cache(path: &Path) -> Result<Self> {
+        FlowField::load(path)
+    }
+}
+
+/// Stages of the generation, which only run when their output is needed and can't be found in
+/// the cache.
+///
+/// Each stage declares its parameters and the stages whose outputs it uses, and its output is
+/// cached under a key hashed from them. Changing a parameter therefore only runs the stages which
+/// depend on it again, and the outputs of the others are loaded from the cache if they are needed
+/// at all.
+pub struct Pipeline<'a> {
+    logger: &'a Logger,
+    /// directory of the cached outputs, nothing is cached without it
+    cache: Option<PathBuf>,
+}
+
+impl<'a> Pipeline<'a> {
+    pub fn new(logger: &'a Logger, cache: Option<PathBuf>) -> Result<Self> {
+        if let Some(dir) = &cache {
+            std::fs::create_dir_all(dir)?;
+        }
+        Ok(Self { logger, cache })
+    }
+
+    /// Starts declaring a stage. Its `name` identifies its cache files and `title` is logged
+    /// while it runs.
+    pub fn stage(&'a self, name: &'static str, title: &'static str) -> StageBuilder<'a> {
+        StageBuilder {
+            pipeline: self,
+            name,
+            title,
+            key: Key::new(name),
+            inputs: Vec::new(),
+        }
+    }
+}
+
+/// Declaration of a stage, see [`Pipeline::stage`](./struct.Pipeline.html#method.stage)
+pub struct StageBuilder<'a> {
+    pipeline: &'a Pipeline<'a>,
+    name: &'static str,
+    title: &'static str,
+    key: Key,
+    inputs: Vec<&'a dyn Input>,
+}
+
+impl<'a> StageBuilder<'a> {
+    /// Adds a parameter the output depends on, which is compared by its debug representation.
+    ///
+    /// The representation has to be the same in every run, so parameters can't contain pointers
+    /// like functions.
+    pub fn param(mut self, param: &impl Debug) -> Self {
+        self.key = self.key.hash(format!("{:?}", param).as_bytes());
+        self
+    }
+
+    /// Adds a stage whose output is used by this one, which is made ready before this one runs
+    pub fn input<T: Cacheable>(mut self, stage: &'a Stage<'a, T>) -> Self {
+        self.key = self.key.hash(&stage.key.0.to_le_bytes());
+        self.inputs.push(stage);
+        self
+    }
+
+    /// Finishes the stage, which generates its output with `run`.
+    ///
+    /// `run` may only use the outputs of the inputs of the stage and its parameters, everything
+    /// else isn't part of the key of the output.
+    pub fn build<T: Cacheable>(self, run: impl Fn() -> Result<T> + 'a) -> Stage<'a, T> {
+        Stage {
+            pipeline: self.pipeline,
+            name: self.name,
+            title: self.title,
+            key: self.key,
+            inputs: self.inputs,
+            run: Box::new(run),
+            output: RefCell::new(None),
+        }
+    }
+}
+
+/// Stage of a [`Pipeline`](./struct.Pipeline.html)
+pub struct Stage<'a, T> {
+    pipeline: &'a Pipeline<'a>,
+    name: &'static str,
+    title: &'static str,
+    key: Key,
+    inputs: Vec<&'a dyn Input>,
+    run: Box<dyn Fn() -> Result<T> + 'a>,
+    output: RefCell<Option<Rc<T>>>,
+}
+
+impl<'a, T: Cacheable> Stage<'a, T> {
+    #[allow(unused)]
+    pub fn key(&self) -> Key {
+        self.key
+    }
+
+    /// Output of the stage, which is kept in memory once it has been loaded from the cache or
+    /// generated. If the cache file can't be loaded, the output is generated again and replaces it.
+    pub fn get(&self) -> Result<Rc<T>> {
+        if let Some(output) = &*self.output.borrow() {
+            return Ok(output.clone());
+        }
+
+        let logger = self.pipeline.logger;
+        let path = self.pipeline.cache.as_ref().map(|dir| {
+            dir.join(format!(
+                "{}-{:016x}.{}",
+                self.name,
+                self.key.0,
+                T::EXTENSION
+            ))
+        });
+
+        let cached = match &path {
+            Some(path) if path.exists() => {
+                match logger.do_task(&format!("Loading {}", self.name), || T::read_cache(path)) {
+                    Ok(output) => Some(output),
+                    // for example a file of an older version or a damaged one, which is replaced
+                    // by the output generated again
+                    Err(err) => {
+                        eprintln!("Can't load {:?}, generating it again: {}", path, err);
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+
+        let output = match cached {
+            Some(output) => output,
+            None => {
+                for input in self.inputs.iter() {
+                    input.prepare()?;
+                }
+                let output = logger.do_task(self.title, &self.run)?;
+                if let Some(path) = &path {
+                    logger.do_task(&format!("Caching {}", self.name), || {
+                        // written under another name first, so that no partial file is loaded
+                        // if the run is interrupted
+                        let partial = path.with_extension("partial");
+                        output.write_cache(&partial)?;
+                        std::fs::rename(&partial, path)
+                    })?;
+                }
+                output
+            }
+        };
+
+        let output = Rc::new(output);
+        *self.output.borrow_mut() = Some(output.clone());
+        Ok(output)
+    }
+
+    /// Frees the memory of the output, which is loaded or generated again if it is needed later
+    pub fn release(&self) {
+        self.output.borrow_mut().take();
+    }
+}
+
+/// Stage whose output another stage uses
+trait Input {
+    /// Loads or generates the output, so that it is ready to be used
+    fn prepare(&self) -> Result<()>;
+}
+
+impl<'a, T: Cacheable> Input for Stage<'a, T> {
+    fn prepare(&self) -> Result<()> {
+        self.get().map(|_| ())
+    }
+}
+
+/// Check that stages only run when their output isn't cached, and run again when one of their
+/// parameters or inputs change or their cache file is broken
+#[test]
+fn test_stage_caching() {
+    use std::cell::Cell;
+
+    let dir = std::env::temp_dir().join(format!("islands-pipeline-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let logger = Logger::new();
+    let runs = Cell::new(0);
+
+    let generate = |base: f32, offset: f32| -> Result<(f32, usize)> {
+        let pipeline = Pipeline::new(&logger, Some(dir.clone()))?;
+        let base_stage = pipeline
+            .stage("base", "Generating Base")
+            .param(&base)
+            .build(|| {
+                runs.set(runs.get() + 1);
+                let mut map = Map::new(4, 3);
+                map.map(|_| base);
+                Ok(map)
+            });
+        let sum = pipeline
+            .stage("sum", "Generating Sum")
+            .param(&offset)
+            .input(&base_stage)
+            .build(|| {
+                runs.set(runs.get() + 1);
+                Ok(&*base_stage.get()? + offset)
+            });
+        let value = sum.get()?[(3, 2)];
+        Ok((value, runs.replace(0)))
+    };
+
+    assert_eq!(generate(1.0, 2.0).unwrap(), (3.0, 2));
+    // everything is cached, and the base isn't even loaded
+    assert_eq!(generate(1.0, 2.0).unwrap(), (3.0, 0));
+    assert_eq!(generate(1.0, 5.0).unwrap(), (6.0, 1));
+    assert_eq!(generate(2.0, 5.0).unwrap(), (7.0, 2));
+    assert_eq!(generate(1.0, 2.0).unwrap(), (3.0, 0));
+
+    // broken cache files are generated again and replaced
+    for entry in std::fs::read_dir(&dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .starts_with("sum-")
+        {
+            std::fs::write(&path, b"broken").unwrap();
+        }
+    }
+    assert_eq!(generate(1.0, 2.0).unwrap(), (3.0, 1));
+    assert_eq!(generate(1.0, 2.0).unwrap(), (3.0, 0));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
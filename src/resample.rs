@@ -0,0 +1,211 @@
+use crate::map::*;
+
+/// How the values between the cells are interpolated when resizing a map
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[allow(unused)]
+pub enum Filter {
+    /// value of the closest cell, which keeps hard edges
+    Nearest,
+    /// linear blend of the four surrounding cells
+    Bilinear,
+    /// cubic Catmull-Rom spline through the sixteen surrounding cells, which is smoother than
+    /// bilinear interpolation but may overshoot a little at sharp edges
+    Bicubic,
+}
+
+/// Resampling, cropping and blurring of maps.
+///
+/// Positions between cells are given in cells, the center of a cell lying at whole numbers.
+/// Positions outside of the map take the values at its border.
+#[allow(unused)]
+impl Map {
+    /// Bilinearly interpolated value at a position between the cells
+    pub fn sample_bilinear(&self, x: f32, y: f32) -> f32 {
+        let x = x.max(0.0).min((self.width() - 1) as f32);
+        let y = y.max(0.0).min((self.height() - 1) as f32);
+        let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+        let (x1, y1) = (
+            (x0 + 1).min(self.width() - 1),
+            (y0 + 1).min(self.height() - 1),
+        );
+        let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+
+        let top = self[(x0, y0)] * (1.0 - fx) + self[(x1, y0)] * fx;
+        let bottom = self[(x0, y1)] * (1.0 - fx) + self[(x1, y1)] * fx;
+        top * (1.0 - fy) + bottom * fy
+    }
+
+    /// Value at a position between the cells, interpolated with a Catmull-Rom spline
+    pub fn sample_bicubic(&self, x: f32, y: f32) -> f32 {
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as isize, y0 as isize);
+
+        let mut rows = [0.0; 4];
+        for (dy, row) in rows.iter_mut().enumerate() {
+            let y = y0 + dy as isize - 1;
+            let p = |dx: isize| self.get_clamped(x0 + dx - 1, y);
+            *row = catmull_rom(p(0), p(1), p(2), p(3), fx);
+        }
+        catmull_rom(rows[0], rows[1], rows[2], rows[3], fy)
+    }
+
+    /// Value at a position between the cells, interpolated with `filter`
+    pub fn sample(&self, x: f32, y: f32, filter: Filter) -> f32 {
+        match filter {
+            Filter::Nearest => self.get_clamped(x.round() as isize, y.round() as isize),
+            Filter::Bilinear => self.sample_bilinear(x, y),
+            Filter::Bicubic => self.sample_bicubic(x, y),
+        }
+    }
+
+    /// Map of another resolution covering the same area.
+    ///
+    /// When shrinking a map by a large factor, blurring it first avoids aliasing.
+    pub fn resize(&self, width: usize, height: usize, filter: Filter) -> Map {
+        let sx = self.width() as f32 / width as f32;
+        let sy = self.height() as f32 / height as f32;
+        let mut map = Map::new(width, height);
+        // the corners of the maps line up, not the centers of their corner cells
+        map.map_coords(|x, y, _| {
+            let x = (x as f32 + 0.5) * sx - 0.5;
+            let y = (y as f32 + 0.5) * sy - 0.5;
+            self.sample(x, y, filter)
+        });
+        map
+    }
+
+    /// Part of the map of the given size with its top left corner at `(x, y)`
+    pub fn crop(&self, x: usize, y: usize, width: usize, height: usize) -> Map {
+        assert!(
+            x + width <= self.width() && y + height <= self.height(),
+            "crop outside of the map"
+        );
+        let mut map = Map::new(width, height);
+        map.map_coords(|cx, cy, _| self[(x + cx, y + cy)]);
+        map
+    }
+
+    /// Map extended by a border of cells with the given `value` on each side
+    pub fn pad(&self, left: usize, top: usize, right: usize, bottom: usize, value: f32) -> Map {
+        let mut map = Map::new(left + self.width() + right, top + self.height() + bottom);
+        map.map_coords(|x, y, _| {
+            if x < left || y < top || x >= left + self.width() || y >= top + self.height() {
+                value
+            } else {
+                self[(x - left, y - top)]
+            }
+        });
+        map
+    }
+
+    /// Average over a square of `2 * radius + 1` cells on each side around each cell
+    pub fn blur_box(&self, radius: usize) -> Map {
+        let kernel = vec![1.0 / (2 * radius + 1) as f32; 2 * radius + 1];
+        self.convolve_separable(&kernel)
+    }
+
+    /// Blur with a Gaussian kernel with a standard deviation of `sigma` cells, which leaves the
+    /// map as it is if `sigma` isn't positive
+    pub fn blur_gaussian(&self, sigma: f32) -> Map {
+        if sigma <= 0.0 {
+            return self.clone();
+        }
+        let radius = (3.0 * sigma).ceil().max(0.0) as isize;
+        let weight = |i: isize| (-0.5 * (i as f32 / sigma).powi(2)).exp();
+        let total: f32 = (-radius..=radius).map(weight).sum();
+        let kernel: Vec<f32> = (-radius..=radius).map(|i| weight(i) / total).collect();
+        self.convolve_separable(&kernel)
+    }
+
+    /// Convolves the map with `kernel` along the rows and then along the columns, the kernel
+    /// being centered on each cell
+    fn convolve_separable(&self, kernel: &[f32]) -> Map {
+        let radius = (kernel.len() / 2) as isize;
+        let convolve = |map: &Map, dx: isize, dy: isize| {
+            let mut blurred = Map::new(map.width(), map.height());
+            blurred.map_coords(|x, y, _| {
+                let (x, y) = (x as isize, y as isize);
+                kernel
+                    .iter()
+                    .zip(-radius..=radius)
+                    .map(|(k, i)| k * map.get_clamped(x + i * dx, y + i * dy))
+                    .sum()
+            });
+            blurred
+        };
+        let rows = convolve(self, 1, 0);
+        convolve(&rows, 0, 1)
+    }
+}
+
+/// Catmull-Rom spline through `p1` at `t = 0` and `p2` at `t = 1`
+fn catmull_rom(p0: f32, p1: f32, p2: f32, p3: f32, t: f32) -> f32 {
+    let a = -0.5 * p0 + 1.5 * p1 - 1.5 * p2 + 0.5 * p3;
+    let b = p0 - 2.5 * p1 + 2.0 * p2 - 0.5 * p3;
+    let c = -0.5 * p0 + 0.5 * p2;
+    ((a * t + b) * t + c) * t + p1
+}
+
+/// Check that the filters reproduce a linear slope, and that nearest neighbor resizing repeats
+/// cells
+#[test]
+fn test_resize() {
+    let mut ramp = Map::new(40, 30);
+    ramp.map_coords(|x, y, _| 2.0 * x as f32 + y as f32);
+
+    for &filter in [Filter::Bilinear, Filter::Bicubic].iter() {
+        let resized = ramp.resize(80, 60, filter);
+        assert_eq!((resized.width(), resized.height()), (80, 60));
+        // the border of the map is flat, as positions outside of it take the border values
+        for x in 4..76 {
+            for y in 4..56 {
+                let expected = 2.0 * (x as f32 * 0.5 - 0.25) + (y as f32 * 0.5 - 0.25);
+                assert!((resized[(x, y)] - expected).abs() < 1e-3);
+            }
+        }
+    }
+
+    let resized = ramp.resize(120, 30, Filter::Nearest);
+    for x in 0..120 {
+        assert_eq!(resized[(x, 7)], ramp[(x / 3, 7)]);
+    }
+    let shrunk = ramp.resize(20, 15, Filter::Bilinear);
+    assert_eq!(shrunk[(3, 4)], 0.5 * (ramp[(6, 8)] + ramp[(7, 9)]));
+}
+
+/// Check cropping and padding, and that blurring keeps flat areas and the total of a spike
+#[test]
+fn test_crop_pad_blur() {
+    let mut map = Map::new(30, 20);
+    map.map_coords(|x, y, _| (x + 100 * y) as f32);
+
+    let cropped = map.crop(5, 3, 10, 4);
+    assert_eq!((cropped.width(), cropped.height()), (10, 4));
+    assert_eq!(cropped[(2, 1)], map[(7, 4)]);
+
+    let padded = map.pad(1, 2, 3, 4, -1.0);
+    assert_eq!((padded.width(), padded.height()), (34, 26));
+    assert_eq!(padded[(0, 5)], -1.0);
+    assert_eq!(padded[(31, 5)], -1.0);
+    assert_eq!(padded[(4, 1)], -1.0);
+    assert_eq!(padded[(4, 22)], -1.0);
+    assert_eq!(padded[(4, 5)], map[(3, 3)]);
+
+    let mut spike = Map::new(41, 41);
+    spike[(20, 20)] = 1.0;
+    for blurred in [spike.blur_box(3), spike.blur_gaussian(2.0)].iter() {
+        assert!((blurred.sum() - 1.0).abs() < 1e-5);
+        assert_eq!(blurred[(0, 0)], 0.0);
+        assert!(blurred[(20, 20)] < 0.2 && blurred[(20, 20)] >= blurred[(21, 20)]);
+        assert!(blurred[(21, 20)] > blurred[(24, 20)]);
+        assert_eq!(blurred[(18, 20)], blurred[(22, 20)]);
+    }
+    assert!((spike.blur_box(3)[(23, 23)] - 1.0 / 49.0).abs() < 1e-6);
+    assert_eq!(spike.blur_box(3)[(24, 23)], 0.0);
+    assert_eq!(spike.blur_gaussian(0.0).values(), spike.values());
+
+    let mut flat = Map::new(20, 20);
+    flat.map(|_| 3.0);
+    assert!((flat.blur_gaussian(4.0)[(0, 10)] - 3.0).abs() < 1e-5);
+}
@@ -0,0 +1,245 @@
+use crate::{geometry::*, map::*};
+use rand::prelude::*;
+
+/// Mountain ridge along a polyline
+#[derive(Clone, Debug)]
+pub struct Ridge {
+    pub points: Vec<(f32, f32)>,
+    /// height of the ridge line before noise is applied
+    pub height: f32,
+}
+
+/// Parameters of the ridge mountains
+#[derive(Clone, Debug)]
+pub struct RidgeConfig {
+    /// ridges to raise, ridges along converging tectonic plate boundaries are generated if empty
+    pub ridges: Vec<Ridge>,
+    /// number of tectonic plates used to generate ridges
+    pub plates: usize,
+    /// height of a ridge where two plates collide head-on
+    pub height: f32,
+    /// distance from the ridge line at which the mountains have fallen to about a third of
+    /// their height
+    pub width: f32,
+    /// how much noise varies the height along the ridges, between `0` and `1`
+    pub noise: f32,
+    /// size of the noise features in cells
+    pub noise_scale: f32,
+    /// cells closer than this to the border of the map are faded to zero, as a fraction of the
+    /// shorter side of the map
+    pub border: f32,
+    pub seed: u64,
+}
+
+impl Default for RidgeConfig {
+    fn default() -> Self {
+        Self {
+            ridges: Vec::new(),
+            plates: 7,
+            height: 40.0,
+            width: 60.0,
+            noise: 0.6,
+            noise_scale: 150.0,
+            border: 0.1,
+            seed: 0,
+        }
+    }
+}
+
+/// Generates ridges along the boundaries of seeded tectonic plates.
+///
+/// The plates are the voronoi cells of random points, each moving into a random direction. Where
+/// two plates move towards each other, a ridge is raised along their boundary. The straight
+/// boundary segments are roughened with midpoint displacement.
+pub fn plate_ridges(width: usize, height: usize, config: &RidgeConfig) -> Vec<Ridge> {
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    let (w, h) = (width as f32, height as f32);
+
+    let plates: Vec<_> = (0..config.plates)
+        .map(|_| {
+            let center = (w * rng.gen::<f32>(), h * rng.gen::<f32>());
+            let angle = rng.gen::<f32>() * std::f32::consts::TAU;
+            (center, (angle.cos(), angle.sin()))
+        })
+        .collect();
+
+    let dist2 = |(ax, ay): (f32, f32), (bx, by): (f32, f32)| {
+        let (dx, dy) = (ax - bx, ay - by);
+        dx * dx + dy * dy
+    };
+
+    let mut ridges = Vec::new();
+    for a in 0..plates.len() {
+        for b in a + 1..plates.len() {
+            let ((ca, va), (cb, vb)) = (plates[a], plates[b]);
+            let (nx, ny) = (cb.0 - ca.0, cb.1 - ca.1);
+            let len = nx.hypot(ny);
+            if len == 0.0 {
+                continue;
+            }
+            let (nx, ny) = (nx / len, ny / len);
+
+            // relative motion of the plates along the line connecting them
+            let convergence = (va.0 - vb.0) * nx + (va.1 - vb.1) * ny;
+            if convergence <= 0.0 {
+                continue;
+            }
+
+            // walk along the bisector of both centers, and keep the part where no other plate is
+            // closer, which is the shared boundary of the voronoi cells
+            let mid = (0.5 * (ca.0 + cb.0), 0.5 * (ca.1 + cb.1));
+            let (tx, ty) = (-ny, nx);
+            let reach = w.hypot(h);
+            let mut boundary = Vec::new();
+            let mut t = -reach;
+            while t <= reach {
+                let p = (mid.0 + t * tx, mid.1 + t * ty);
+                let inside = p.0 >= 0.0 && p.1 >= 0.0 && p.0 < w && p.1 < h;
+                let shared = plates
+                    .iter()
+                    .all(|&(c, _)| dist2(p, c) >= dist2(p, ca) - 1e-3);
+                if inside && shared {
+                    boundary.push(p);
+                }
+                t += 1.0;
+            }
+
+            if let (Some(&from), Some(&to)) = (boundary.first(), boundary.last()) {
+                ridges.push(Ridge {
+                    points: displace(from, to, 6, 0.25, &mut rng),
+                    height: config.height * convergence.min(1.0),
+                });
+            }
+        }
+    }
+
+    ridges
+}
+
+/// Subdivides the segment `depth` times, moving each new midpoint sideways by up to `roughness`
+/// times the length of its segment
+fn displace(
+    from: (f32, f32),
+    to: (f32, f32),
+    depth: usize,
+    roughness: f32,
+    rng: &mut impl Rng,
+) -> Vec<(f32, f32)> {
+    let mut points = vec![from, to];
+    for _ in 0..depth {
+        let mut refined = Vec::with_capacity(2 * points.len());
+        for pair in points.windows(2) {
+            let ((ax, ay), (bx, by)) = (pair[0], pair[1]);
+            let offset = roughness * (2.0 * rng.gen::<f32>() - 1.0);
+            refined.push(pair[0]);
+            refined.push((
+                0.5 * (ax + bx) - offset * (by - ay),
+                0.5 * (ay + by) + offset * (bx - ax),
+            ));
+        }
+        refined.push(to);
+        points = refined;
+    }
+    points
+}
+
+/// Raises mountains along the ridges.
+///
+/// The height falls off with a gaussian of the distance to the ridge line and is varied by noise,
+/// which breaks each ridge up into several summits. Where ridges overlap, the higher one is kept.
+pub fn ridge_map(width: usize, height: usize, ridges: &[Ridge], config: &RidgeConfig) -> Map {
+    // the gaussian is negligible further away than this
+    let reach = 3.0 * config.width;
+
+    // each small square tile holds the segments within reach of it, so that the cells are
+    // computed in parallel with every cell only looking at the segments close to it
+    let tile = (reach / 8.0).max(1.0);
+    let tiles_x = (width as f32 / tile) as usize + 1;
+    let tiles_y = (height as f32 / tile) as usize + 1;
+    let mut tiles = vec![Vec::new(); tiles_x * tiles_y];
+    for ridge in ridges.iter() {
+        for pair in ridge.points.windows(2) {
+            let (a, b) = (pair[0], pair[1]);
+            let tile_of = |v: f32, tiles: usize| ((v / tile).max(0.0) as usize).min(tiles - 1);
+            let (x0, x1) = (a.0.min(b.0) - reach, a.0.max(b.0) + reach);
+            let (y0, y1) = (a.1.min(b.1) - reach, a.1.max(b.1) + reach);
+            for ty in tile_of(y0, tiles_y)..=tile_of(y1, tiles_y) {
+                for tx in tile_of(x0, tiles_x)..=tile_of(x1, tiles_x) {
+                    let center = ((tx as f32 + 0.5) * tile, (ty as f32 + 0.5) * tile);
+                    if distance_to_segment(center, a, b) < reach + tile {
+                        tiles[tx + ty * tiles_x].push((a, b, ridge.height));
+                    }
+                }
+            }
+        }
+    }
+
+    let simplex = fuss::Simplex::from_seed(vec![config.seed as usize]);
+    let scale = 1.0 / config.noise_scale;
+    let (w, h) = (width as f32, height as f32);
+    let border = config.border * w.min(h);
+
+    let mut map = Map::new(width, height);
+    map.map_coords(|x, y, _| {
+        let (x, y) = (x as f32, y as f32);
+        let segments = &tiles[(x / tile) as usize + (y / tile) as usize * tiles_x];
+        let v = segments.iter().fold(0.0f32, |v, &(a, b, height)| {
+            let d = distance_to_segment((x, y), a, b);
+            if d < reach {
+                let d = d / config.width;
+                v.max(height * (-d * d).exp())
+            } else {
+                v
+            }
+        });
+        if v == 0.0 {
+            return 0.0;
+        }
+
+        let noise = simplex.sum_octave_2d(3, x, y, 0.5, scale);
+        let edge = x.min(y).min(w - 1.0 - x).min(h - 1.0 - y) / border;
+        let fade = edge.clamp(0.0, 1.0);
+        v * (1.0 + config.noise * noise).max(0.0) * fade * fade * (3.0 - 2.0 * fade)
+    });
+
+    map
+}
+
+/// Raises the ridges of the config, or generated plate ridges if there are none
+pub fn raise_ridges(map: &Map, config: &RidgeConfig) -> Map {
+    let (width, height) = (map.width(), map.height());
+    let ridges = if config.ridges.is_empty() {
+        plate_ridges(width, height, config)
+    } else {
+        config.ridges.clone()
+    };
+    map + &ridge_map(width, height, &ridges, config)
+}
+
+/// Check that a ridge is highest on its line and falls off to the sides
+#[test]
+fn test_ridge_falloff() {
+    let config = RidgeConfig {
+        ridges: vec![Ridge {
+            points: vec![(20.0, 64.0), (108.0, 64.0)],
+            height: 10.0,
+        }],
+        width: 8.0,
+        noise: 0.0,
+        border: 0.01,
+        ..RidgeConfig::default()
+    };
+    let map = raise_ridges(&Map::new(128, 128), &config);
+
+    assert!((map[(64, 64)] - 10.0).abs() < 1e-4);
+    assert!(map[(64, 60)] < map[(64, 64)]);
+    assert!(map[(64, 56)] < map[(64, 60)]);
+    assert!((map[(64, 60)] - map[(64, 68)]).abs() < 1e-4);
+    assert!(map[(64, 120)] < 1e-3);
+
+    // generated ridges are deterministic
+    let config = RidgeConfig::default();
+    let ridges = plate_ridges(256, 256, &config);
+    assert!(!ridges.is_empty());
+    assert_eq!(ridges[0].points, plate_ridges(256, 256, &config)[0].points);
+}
@@ -1,47 +1,180 @@
-use crate::{draw::*, map::*};
+use crate::{buffer::*, draw::*, flow::*, map::*};
+use rayon::prelude::*;
+use std::sync::atomic::{AtomicU32, Ordering::*};
+
+/// Number of rows of the flow map that are drawn together
+const FLOW_BAND: usize = 64;
 
 /// Calculates the flow map of the terrain given in `map`.
 ///
 /// It does so by distributing water on each cell of the map and drawing
 /// all of the waters path onto the flow_map, which it returns.
 ///
-pub fn create_flow_map(map: &Map, cell_targets: &Vec<Vec<(usize, usize)>>) -> Map {
+/// If the targets wrap around the edges, paths crossing the edges are drawn the short way around.
+///
+/// The volumes are gathered in parallel, each cell being processed once all the cells flowing into
+/// it are. The paths are then drawn in parallel bands of rows. The volumes are whole numbers of
+/// cells, which are summed up as integers, so the result doesn't depend on the order the threads
+/// get to the cells in.
+///
+/// The sums are exact and only rounded to `f32` at the end. Summing in `f32` like the sequential
+/// implementation did loses water once a sum passes `2^24`, which happens at the mouths of the
+/// large rivers of big maps, so there the results differ and this one is the intended one.
+pub fn create_flow_map(map: &Map, cell_targets: &FlowField) -> Map {
     let width = map.width();
     let height = map.height();
+    let wrap = cell_targets.is_wrapped();
+
+    // how many cells 'target' this cell, plus one for the cell itself, which is released when the
+    // cell is visited below
+    let targets = Buffer::<AtomicU32>::zeroed(width * height);
+    // how much volume flows into each cell from the cells targeting it
+    let volume = Buffer::<AtomicU32>::zeroed(width * height);
+
+    targets.par_iter().for_each(|t| t.store(1, Relaxed));
+
+    // finding how many times each cell is targeted
+    (0..height).into_par_iter().for_each(|y| {
+        for x in 0..width {
+            if !cell_targets.is_sink(x, y) {
+                targets[cell_targets[(x, y)] as usize].fetch_add(1, Relaxed);
+            }
+        }
+    });
+
+    // whoever releases the last reference to a cell, be it the cell itself or the last cell
+    // flowing into it, passes its volume on, which makes its target ready the same way
+    let release = |idx: usize| {
+        // if only one reference is left, it is the one being released, so no one else can change
+        // the count anymore
+        targets[idx].load(Acquire) == 1 || targets[idx].fetch_sub(1, AcqRel) == 1
+    };
+
+    (0..height).into_par_iter().for_each(|y| {
+        for x in 0..width {
+            let mut idx = map.flatten_xy(x, y);
+            while release(idx) {
+                let next = cell_targets[map.unflatten(idx)] as usize;
+                if next == idx {
+                    break;
+                }
+                volume[next].fetch_add(1 + volume[idx].load(Relaxed), Relaxed);
+                idx = next;
+            }
+        }
+    });
+
+    let range = cell_targets.range();
+    let mut flow_map = Map::new(width, height);
+    flow_map.par_bands_mut(FLOW_BAND, |rows, values| {
+        // sum of the volumes of all paths drawn through each cell of the band
+        let mut flow = vec![0u64; values.len()];
+
+        // paths are shorter than the range, so only the cells this close to the band can reach it
+        let sources: Vec<_> = if !wrap {
+            rows.start.saturating_sub(range)..(rows.end + range).min(height)
+        } else if rows.len() + 2 * range >= height {
+            0..height
+        } else {
+            (rows.start + height - range)..(rows.end + height + range)
+        }
+        .map(|y| y % height)
+        .collect();
+
+        for y in sources {
+            for x in 0..width {
+                let idx = map.flatten_xy(x, y);
+                let (nx, ny) = cell_targets.target(x, y);
+                let stroke = 1 + volume[idx].load(Relaxed) as u64;
+
+                let plot = |x: isize, y: isize| {
+                    let (x, y) = if wrap {
+                        (x.rem_euclid(width as _), y.rem_euclid(height as _))
+                    } else {
+                        (x, y)
+                    };
+                    let y = y as usize;
+                    if rows.contains(&y) {
+                        flow[x as usize + (y - rows.start) * width] += stroke;
+                    }
+                };
+                if wrap {
+                    let (ux, uy) = (unwrap(x, nx, width), unwrap(y, ny, height));
+                    rasterize_line(x as _, y as _, ux, uy, plot);
+                } else {
+                    rasterize_line(x as _, y as _, nx as _, ny as _, plot);
+                }
+            }
+        }
+
+        for (value, flow) in values.iter_mut().zip(flow.iter()) {
+            *value = *flow as f32;
+        }
+    });
+    flow_map
+}
+
+/// Returns the coordinate of `t` as seen from `v` on a tileable axis of length `len`.
+///
+/// The target is close by, so it is on the other side if it seems far away.
+fn unwrap(v: usize, t: usize, len: usize) -> isize {
+    let d = t as isize - v as isize;
+    let len = len as isize;
+    if 2 * d > len {
+        t as isize - len
+    } else if 2 * d < -len {
+        t as isize + len
+    } else {
+        t as isize
+    }
+}
+
+/// Calculates the flow map one path at a time, the way it was done before it was parallelised
+#[cfg(test)]
+fn sequential_flow_map(map: &Map, cell_targets: &FlowField) -> Map {
+    let width = map.width();
+    let height = map.height();
+    let wrap = cell_targets.is_wrapped();
     let mut flow_map = Map::new(width, height);
 
     // how many other cells 'target' this cell
-    let mut targets = vec![vec![0i32; height]; width];
+    let mut targets = Buffer::<i32>::zeroed(width * height);
     // how much volume flows through each cell
-    let mut volume = vec![vec![1.0f32; height]; width];
+    let mut volume = Buffer::<f32>::zeroed(width * height);
+    volume.fill(1.0);
 
     // finding how many times each cell is targeted
-    for x in 0..width {
-        for y in 0..height {
-            let (nx, ny) = cell_targets[x][y];
-            if (nx, ny) != (x, y) {
-                targets[nx][ny] += 1;
+    for y in 0..height {
+        for x in 0..width {
+            if !cell_targets.is_sink(x, y) {
+                targets[cell_targets[(x, y)] as usize] += 1;
             }
         }
     }
 
-    for x in 0..width {
-        for y in 0..height {
-            if targets[x][y] == 0 {
+    for y in 0..height {
+        for x in 0..width {
+            if targets[map.flatten_xy(x, y)] == 0 {
                 let (mut x, mut y) = (x, y);
                 loop {
+                    let idx = map.flatten_xy(x, y);
                     // prevent processing this cell twice
-                    targets[x][y] = -1;
+                    targets[idx] = -1;
 
-                    let (nx, ny) = cell_targets[x][y];
+                    let next = cell_targets[(x, y)] as usize;
+                    let (nx, ny) = map.unflatten(next);
 
-                    draw_line(&mut flow_map, x as _, y as _, nx as _, ny as _, &|h| {
-                        h + volume[x][y]
-                    });
-                    volume[nx][ny] += volume[x][y];
-                    targets[nx][ny] -= 1;
+                    let stroke = |h| h + volume[idx];
+                    if wrap {
+                        let (ux, uy) = (unwrap(x, nx, width), unwrap(y, ny, height));
+                        draw_line_wrapped(&mut flow_map, x as _, y as _, ux, uy, stroke);
+                    } else {
+                        draw_line(&mut flow_map, x as _, y as _, nx as _, ny as _, stroke);
+                    }
+                    volume[next] += volume[idx];
+                    targets[next] -= 1;
 
-                    if targets[nx][ny] == 0 {
+                    if targets[next] == 0 {
                         // if all the cells which target (nx, ny) have been processed, (nx, ny) can
                         // now be processed
                         x = nx;
@@ -56,3 +189,62 @@ pub fn create_flow_map(map: &Map, cell_targets: &Vec<Vec<(usize, usize)>>) -> Ma
 
     flow_map
 }
+
+/// Check that the parallel flow map is exactly the same as the sequential one, on seeded maps with
+/// and without flat areas, on tileable maps and with different numbers of threads. The maps are
+/// small enough for the sums of the sequential one to stay below `2^24`, where `f32` is exact.
+#[test]
+fn test_parallel_flow_map() {
+    use rand::prelude::*;
+
+    for seed in 0..6 {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let (width, height) = (rng.gen_range(40..160), rng.gen_range(40..160));
+        let waves: Vec<(f32, f32, f32)> = (0..8)
+            .map(|_| {
+                (
+                    rng.gen_range(0.02..0.2),
+                    rng.gen_range(0.02..0.2),
+                    rng.gen(),
+                )
+            })
+            .collect();
+
+        let mut map = Map::new(width, height);
+        map.map_coords(|x, y, _| {
+            let (dx, dy) = (
+                x as f32 - 0.5 * width as f32,
+                y as f32 - 0.5 * height as f32,
+            );
+            let bowl = 0.01 * (dx * dx + dy * dy).sqrt();
+            let noise: f32 = waves
+                .iter()
+                .map(|&(fx, fy, phase)| (fx * x as f32 + fy * y as f32 + 6.0 * phase).sin())
+                .sum();
+            bowl + noise
+        });
+        if seed % 2 == 1 {
+            // plateaus, where many cells have the same height
+            map.map(|h| h.round());
+        }
+
+        for &wrap in [false, true].iter() {
+            let targets = find_targets(&map, 6, wrap);
+            let expected = sequential_flow_map(&map, &targets);
+
+            for &threads in [1, 3].iter() {
+                let pool = rayon::ThreadPoolBuilder::new()
+                    .num_threads(threads)
+                    .build()
+                    .unwrap();
+                let flow_map = pool.install(|| create_flow_map(&map, &targets));
+
+                for x in 0..width {
+                    for y in 0..height {
+                        assert_eq!(flow_map[(x, y)], expected[(x, y)]);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,284 @@
+use crate::{geometry::*, splat::*, vis::*};
+use rand::prelude::*;
+use std::f32::consts::*;
+use std::io::{Result, Write};
+
+/// How many candidates are tried around a point before it stops spawning new ones
+const POISSON_CANDIDATES: usize = 30;
+
+/// Kind of object which is scattered over the terrain, like trees or rocks
+#[derive(Clone, Debug)]
+pub struct ObjectType {
+    pub name: &'static str,
+    /// minimum distance between two objects of this type
+    pub spacing: f32,
+    /// probability that an object is placed where all the rules are fully satisfied
+    pub density: f32,
+    /// conditions on where the objects are placed, which scale the density
+    pub rules: Vec<Rule>,
+    /// range of the random scale of the objects
+    pub scale: (f32, f32),
+    /// color of the objects in the debug overlay
+    pub color: [u8; 3],
+}
+
+/// Single object placed on the terrain
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Instance {
+    /// index of the object type
+    pub kind: usize,
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+    /// rotation around the vertical axis in radians
+    pub rotation: f32,
+    pub scale: f32,
+}
+
+/// Rocks, trees and bushes
+pub fn default_object_types() -> Vec<ObjectType> {
+    vec![
+        ObjectType {
+            name: "rock",
+            spacing: 12.0,
+            density: 0.4,
+            rules: vec![
+                Rule::Band {
+                    from: Terrain::WetSand,
+                    to: Terrain::Snow,
+                    blend: 0.0,
+                },
+                Rule::Slope {
+                    min: 25.0,
+                    max: 90.0,
+                    blend: 5.0,
+                },
+            ],
+            scale: (0.5, 2.0),
+            color: [110, 110, 110],
+        },
+        ObjectType {
+            name: "tree",
+            spacing: 5.0,
+            density: 0.7,
+            rules: vec![
+                Rule::Band {
+                    from: Terrain::Grass,
+                    to: Terrain::Grass,
+                    blend: 1.0,
+                },
+                Rule::Slope {
+                    min: 0.0,
+                    max: 30.0,
+                    blend: 5.0,
+                },
+                Rule::LakeDistance {
+                    min: 2.0,
+                    max: f32::MAX,
+                    blend: 1.0,
+                },
+            ],
+            scale: (0.8, 1.2),
+            color: [34, 85, 34],
+        },
+        ObjectType {
+            name: "bush",
+            spacing: 3.0,
+            density: 0.5,
+            rules: vec![
+                Rule::Band {
+                    from: Terrain::Sand,
+                    to: Terrain::Grass,
+                    blend: 1.0,
+                },
+                Rule::RiverDistance {
+                    min: 0.0,
+                    max: 4.0,
+                    blend: 4.0,
+                },
+            ],
+            scale: (0.7, 1.3),
+            color: [90, 130, 50],
+        },
+    ]
+}
+
+/// Scatters objects of all the `types` over the terrain.
+///
+/// Each type is first distributed with Poisson-disk sampling and then thinned out according to
+/// its density and rules. Nothing is placed on cells of the lake map. Types earlier in the list
+/// take precedence: later objects keep the average of both spacings away from them.
+pub fn scatter(input: &SplatInput, types: &[ObjectType], seed: u64) -> Vec<Instance> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let maps = RuleMaps::new(input, types.iter().flat_map(|t| t.rules.iter()));
+    let (width, height) = (input.terrain.width(), input.terrain.height());
+
+    let max_spacing = types.iter().map(|t| t.spacing).fold(1.0, f32::max);
+    let mut placed = SpatialGrid::new(width as f32, height as f32, max_spacing);
+    let mut instances: Vec<Instance> = Vec::new();
+
+    for (kind, object) in types.iter().enumerate() {
+        let candidates = poisson_disk(width as f32, height as f32, object.spacing, &mut rng);
+
+        for (x, y) in candidates.into_iter() {
+            let xy = (x as usize, y as usize);
+            if input.lake[xy] > 0.0 {
+                // nothing is placed in lakes or the sea
+                continue;
+            }
+            let factor: f32 = object
+                .rules
+                .iter()
+                .map(|rule| rule.factor(input, &maps, xy))
+                .product();
+            if rng.gen::<f32>() >= object.density * factor {
+                continue;
+            }
+
+            let blocked = placed.any_near(x, y, max_spacing, |i| {
+                let other = &instances[i];
+                let spacing = 0.5 * (object.spacing + types[other.kind].spacing);
+                other.kind != kind && (other.x - x).hypot(other.y - y) < spacing
+            });
+            if blocked {
+                continue;
+            }
+
+            let (min_scale, max_scale) = object.scale;
+            placed.insert(x, y, instances.len());
+            instances.push(Instance {
+                kind,
+                x,
+                y,
+                z: input.terrain[xy],
+                rotation: rng.gen::<f32>() * 2.0 * PI,
+                scale: min_scale + rng.gen::<f32>() * (max_scale - min_scale),
+            });
+        }
+    }
+
+    instances
+}
+
+/// Generates points within `0..width` and `0..height` which are at least `spacing` apart, using
+/// Bridson's algorithm.
+fn poisson_disk(width: f32, height: f32, spacing: f32, rng: &mut impl Rng) -> Vec<(f32, f32)> {
+    // with this cell size, each cell can hold at most one point
+    let mut grid = SpatialGrid::new(width, height, spacing / SQRT_2);
+    let mut points = Vec::new();
+    let mut active = Vec::new();
+
+    let first = (rng.gen::<f32>() * width, rng.gen::<f32>() * height);
+    grid.insert(first.0, first.1, 0);
+    points.push(first);
+    active.push(0);
+
+    while !active.is_empty() {
+        let i = rng.gen_range(0..active.len());
+        let (px, py) = points[active[i]];
+        let mut spawned = false;
+
+        for _ in 0..POISSON_CANDIDATES {
+            let angle = rng.gen::<f32>() * 2.0 * PI;
+            let dist = spacing * (1.0 + rng.gen::<f32>());
+            let (x, y) = (px + angle.cos() * dist, py + angle.sin() * dist);
+
+            if x < 0.0 || y < 0.0 || x >= width || y >= height {
+                continue;
+            }
+            let too_close = grid.any_near(x, y, spacing, |j| {
+                let (qx, qy) = points[j];
+                (qx - x).hypot(qy - y) < spacing
+            });
+            if too_close {
+                continue;
+            }
+
+            grid.insert(x, y, points.len());
+            active.push(points.len());
+            points.push((x, y));
+            spawned = true;
+            break;
+        }
+
+        if !spawned {
+            active.swap_remove(i);
+        }
+    }
+
+    points
+}
+
+/// Writes the instances as CSV, one line per instance
+#[allow(unused)]
+pub fn write_csv<W: Write>(instances: &[Instance], types: &[ObjectType], mut out: W) -> Result<()> {
+    writeln!(out, "type,x,y,z,rotation,scale")?;
+    for i in instances.iter() {
+        writeln!(
+            out,
+            "{},{},{},{},{},{}",
+            types[i.kind].name, i.x, i.y, i.z, i.rotation, i.scale
+        )?;
+    }
+    Ok(())
+}
+
+/// Writes the instances as a JSON array of objects
+#[allow(unused)]
+pub fn write_json<W: Write>(
+    instances: &[Instance],
+    types: &[ObjectType],
+    mut out: W,
+) -> Result<()> {
+    writeln!(out, "[")?;
+    for (idx, i) in instances.iter().enumerate() {
+        let separator = if idx + 1 < instances.len() { "," } else { "" };
+        writeln!(
+            out,
+            "  {{\"type\": \"{}\", \"x\": {}, \"y\": {}, \"z\": {}, \"rotation\": {}, \"scale\": {}}}{}",
+            types[i.kind].name, i.x, i.y, i.z, i.rotation, i.scale, separator
+        )?;
+    }
+    writeln!(out, "]")
+}
+
+/// Markers of the instances for the debug overlay of the renderer
+pub fn markers(instances: &[Instance], types: &[ObjectType]) -> Vec<Marker> {
+    instances
+        .iter()
+        .map(|i| Marker {
+            x: i.x,
+            y: i.y,
+            radius: (0.3 * types[i.kind].spacing * i.scale).max(1.0),
+            color: types[i.kind].color,
+        })
+        .collect()
+}
+
+/// Check that scattering is deterministic and keeps the spacing between objects of a type
+#[test]
+fn test_scatter_spacing() {
+    use crate::map::*;
+
+    let mut terrain = Map::new(128, 96);
+    terrain.map(|_| 30.0);
+    let empty = Map::new(128, 96);
+    let input = SplatInput {
+        terrain: &terrain,
+        river: &empty,
+        lake: &empty,
+        bands: Bands::new(20.0, 100.0),
+        river_level: 100.0,
+    };
+    let types = default_object_types();
+
+    let instances = scatter(&input, &types, 42);
+    assert!(!instances.is_empty());
+    assert_eq!(instances, scatter(&input, &types, 42));
+
+    for a in instances.iter() {
+        for b in instances.iter().filter(|b| b.kind == a.kind && *b != a) {
+            assert!((a.x - b.x).hypot(a.y - b.y) >= types[a.kind].spacing);
+        }
+    }
+}
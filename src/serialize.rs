@@ -0,0 +1,275 @@
+use crate::{buffer::*, flow::*, map::*, zlib::*};
+use rayon::prelude::*;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Start of every grid file
+const GRID_MAGIC: &[u8; 8] = b"ISLGRID\0";
+/// Start of every flow field file, which is followed by a grid file of the targets
+const FLOW_MAGIC: &[u8; 8] = b"ISLFLOW\0";
+/// Version of the file format, which is increased whenever the layout changes
+pub const FORMAT_VERSION: u32 = 1;
+/// Most bytes that zlib can decompress from a single byte
+const MAX_ZLIB_RATIO: u64 = 1032;
+
+/// Cells which can be saved to a file, as 4 little endian bytes each
+pub trait Storable: Zeroable + Copy {
+    /// identifies the type of the cells in the header
+    const KIND: u8;
+    fn to_bytes(self) -> [u8; 4];
+    fn from_bytes(bytes: [u8; 4]) -> Self;
+}
+
+impl Storable for f32 {
+    const KIND: u8 = 0;
+    fn to_bytes(self) -> [u8; 4] {
+        self.to_le_bytes()
+    }
+    fn from_bytes(bytes: [u8; 4]) -> Self {
+        f32::from_le_bytes(bytes)
+    }
+}
+
+impl Storable for u32 {
+    const KIND: u8 = 1;
+    fn to_bytes(self) -> [u8; 4] {
+        self.to_le_bytes()
+    }
+    fn from_bytes(bytes: [u8; 4]) -> Self {
+        u32::from_le_bytes(bytes)
+    }
+}
+
+/// Binary files of grids, so that the results of a generation run can be loaded again later.
+///
+/// A file starts with a header of 32 bytes: the magic bytes `ISLGRID\0`, the format version as
+/// `u32`, the kind of the cells and whether they are compressed as one byte each, two zero bytes,
+/// and the width and height as `u64`. The cells follow row by row, as little endian bytes which
+/// are optionally compressed with zlib.
+impl<T: Storable> Grid<T> {
+    /// Saves the grid to a file at `path`, see [`write_to`](#method.write_to)
+    pub fn save(&self, path: impl AsRef<Path>, compress: bool) -> Result<()> {
+        self.write_to(BufWriter::new(File::create(path)?), compress)
+    }
+
+    /// Loads a grid from a file at `path`, see [`read_from`](#method.read_from)
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        Self::read_from(BufReader::new(File::open(path)?))
+    }
+
+    /// Writes the grid to `writer`, compressing the cells if `compress` is set
+    pub fn write_to<W: Write>(&self, mut writer: W, compress: bool) -> Result<()> {
+        let mut header = Vec::with_capacity(32);
+        header.extend_from_slice(GRID_MAGIC);
+        header.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        header.extend_from_slice(&[T::KIND, compress as u8, 0, 0]);
+        header.extend_from_slice(&(self.width() as u64).to_le_bytes());
+        header.extend_from_slice(&(self.height() as u64).to_le_bytes());
+        writer.write_all(&header)?;
+
+        if compress {
+            let mut zlib = ZlibWriter::new(writer, 1);
+            self.write_cells(&mut zlib)?;
+            zlib.finish()?.flush()
+        } else {
+            self.write_cells(&mut writer)?;
+            writer.flush()
+        }
+    }
+
+    /// Reads a grid written by [`write_to`](#method.write_to) from `reader`.
+    ///
+    /// Files of another format version or with another kind of cells are rejected, as well as
+    /// files which don't contain enough data for the size in their header.
+    pub fn read_from<R: Read + Seek>(mut reader: R) -> Result<Self> {
+        let mut header = [0; 32];
+        reader.read_exact(&mut header)?;
+        if &header[..8] != GRID_MAGIC {
+            return Err(invalid_data("not a grid file".to_string()));
+        }
+        let version = u32::from_le_bytes(array(&header[8..12]));
+        if version != FORMAT_VERSION {
+            return Err(invalid_data(format!(
+                "grid file of version {}, expected {}",
+                version, FORMAT_VERSION
+            )));
+        }
+        if header[12] != T::KIND {
+            return Err(invalid_data(format!(
+                "grid file with cells of kind {}, expected {}",
+                header[12],
+                T::KIND
+            )));
+        }
+        let compressed = header[13] != 0;
+        let width = u64::from_le_bytes(array(&header[16..24])) as usize;
+        let height = u64::from_le_bytes(array(&header[24..32])) as usize;
+        let len = (width as u64)
+            .checked_mul(height as u64)
+            .and_then(|cells| cells.checked_mul(4))
+            .ok_or_else(|| invalid_data(format!("grid of {}x{} cells too large", width, height)))?;
+
+        // the size in the header is checked against the data before the grid is allocated, so
+        // that a broken header can't ask for more memory than there is
+        let start = reader.stream_position()?;
+        let data_len = reader.seek(SeekFrom::End(0))? - start;
+        reader.seek(SeekFrom::Start(start))?;
+        let fits = if compressed {
+            len <= data_len.saturating_mul(MAX_ZLIB_RATIO)
+        } else {
+            len == data_len
+        };
+        if !fits {
+            return Err(invalid_data(format!(
+                "grid data of {} bytes for {}x{} cells",
+                data_len, width, height
+            )));
+        }
+
+        let mut grid = Grid::new(width, height);
+        if compressed {
+            let mut zlib = ZlibReader::new(reader);
+            grid.read_cells(&mut zlib)?;
+            if zlib.read(&mut [0])? != 0 {
+                return Err(invalid_data("grid data after the last cell".to_string()));
+            }
+        } else {
+            grid.read_cells(&mut reader)?;
+        }
+        Ok(grid)
+    }
+
+    /// Reads the cells row by row, so that large grids are never in memory twice
+    fn read_cells(&mut self, reader: &mut impl Read) -> Result<()> {
+        let width = self.width().max(1);
+        let mut bytes = vec![0; 4 * width];
+        for row in self.values_mut().chunks_mut(width) {
+            reader.read_exact(&mut bytes)?;
+            for (cell, bytes) in row.iter_mut().zip(bytes.chunks_exact(4)) {
+                *cell = T::from_bytes(array(bytes));
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the cells row by row, so that large grids are never in memory twice
+    fn write_cells(&self, writer: &mut impl Write) -> Result<()> {
+        let mut bytes = Vec::with_capacity(4 * self.width());
+        for row in self.values().chunks(self.width().max(1)) {
+            bytes.clear();
+            for &cell in row.iter() {
+                bytes.extend_from_slice(&cell.to_bytes());
+            }
+            writer.write_all(&bytes)?;
+        }
+        Ok(())
+    }
+}
+
+/// Binary files of flow fields.
+///
+/// A file starts with a header of 16 bytes: the magic bytes `ISLFLOW\0`, the format version as
+/// `u32`, the range of the targets as `u16`, one byte for whether they wrap around the edges and
+/// a zero byte. The grid of the targets follows as grid file.
+impl FlowField {
+    /// Saves the flow field to a file at `path`, see [`write_to`](#method.write_to)
+    pub fn save(&self, path: impl AsRef<Path>, compress: bool) -> Result<()> {
+        self.write_to(BufWriter::new(File::create(path)?), compress)
+    }
+
+    /// Loads a flow field from a file at `path`, see [`read_from`](#method.read_from)
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        Self::read_from(BufReader::new(File::open(path)?))
+    }
+
+    /// Writes the flow field to `writer`, compressing the targets if `compress` is set
+    pub fn write_to<W: Write>(&self, mut writer: W, compress: bool) -> Result<()> {
+        let mut header = Vec::with_capacity(16);
+        header.extend_from_slice(FLOW_MAGIC);
+        header.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        header.extend_from_slice(&(self.range() as u16).to_le_bytes());
+        header.extend_from_slice(&[self.is_wrapped() as u8, 0]);
+        writer.write_all(&header)?;
+        self.targets().write_to(writer, compress)
+    }
+
+    /// Reads a flow field written by [`write_to`](#method.write_to) from `reader`
+    pub fn read_from<R: Read + Seek>(mut reader: R) -> Result<Self> {
+        let mut header = [0; 16];
+        reader.read_exact(&mut header)?;
+        if &header[..8] != FLOW_MAGIC {
+            return Err(invalid_data("not a flow field file".to_string()));
+        }
+        let version = u32::from_le_bytes(array(&header[8..12]));
+        if version != FORMAT_VERSION {
+            return Err(invalid_data(format!(
+                "flow field file of version {}, expected {}",
+                version, FORMAT_VERSION
+            )));
+        }
+        let range = u16::from_le_bytes([header[12], header[13]]) as usize;
+        let wrap = header[14] != 0;
+
+        let targets = Grid::<u32>::read_from(reader)?;
+        let cells = targets.width() * targets.height();
+        // the targets are followed without any checks later on
+        if targets.values().par_iter().any(|&t| t as usize >= cells) {
+            return Err(invalid_data("flow target outside of the map".to_string()));
+        }
+        Ok(FlowField::from_targets(targets, range, wrap))
+    }
+}
+
+fn array<const N: usize>(slice: &[u8]) -> [u8; N] {
+    slice.try_into().unwrap()
+}
+
+fn invalid_data(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+/// Check that maps and flow fields come back the same from compressed and uncompressed files,
+/// and that other files are rejected
+#[test]
+fn test_save_load() {
+    use crate::simplex::*;
+    use std::io::Cursor;
+
+    let map = simplex_map(123, 45);
+    let targets = find_targets(&map, 6, true);
+
+    for &compress in [false, true].iter() {
+        let mut file = Vec::new();
+        map.write_to(&mut file, compress).unwrap();
+        let loaded = Map::read_from(Cursor::new(&file[..])).unwrap();
+        assert_eq!((loaded.width(), loaded.height()), (123, 45));
+        assert_eq!(loaded.values(), map.values());
+
+        let mut file = Vec::new();
+        targets.write_to(&mut file, compress).unwrap();
+        let loaded = FlowField::read_from(Cursor::new(&file[..])).unwrap();
+        assert_eq!(loaded.targets().values(), targets.targets().values());
+        assert_eq!(loaded.range(), 6);
+        assert!(loaded.is_wrapped());
+    }
+
+    let mut file = Vec::new();
+    map.write_to(&mut file, false).unwrap();
+    assert!(Grid::<u32>::read_from(Cursor::new(&file[..])).is_err());
+    assert!(Map::read_from(Cursor::new(&file[..file.len() - 1])).is_err());
+    // the header asks for far more cells than there is data for, which must not be allocated
+    let mut huge = file.clone();
+    huge[16..24].copy_from_slice(&(1u64 << 40).to_le_bytes());
+    assert!(Map::read_from(Cursor::new(&huge[..])).is_err());
+    let mut compressed = Vec::new();
+    map.write_to(&mut compressed, true).unwrap();
+    let cut = &compressed[..compressed.len() - 10];
+    assert!(Map::read_from(Cursor::new(cut)).is_err());
+    compressed[16..24].copy_from_slice(&(1u64 << 40).to_le_bytes());
+    assert!(Map::read_from(Cursor::new(&compressed[..])).is_err());
+    file[8] += 1;
+    assert!(Map::read_from(Cursor::new(&file[..])).is_err());
+    assert!(FlowField::read_from(Cursor::new(&file[..])).is_err());
+}
@@ -0,0 +1,384 @@
+use crate::{draw::*, geometry::*, map::*, obj::*, splat::*, vis::*};
+use std::collections::*;
+use std::f32::consts::*;
+use std::io::{Result, Write};
+
+/// Town placed on the terrain
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Town {
+    pub x: usize,
+    pub y: usize,
+    /// how well suited the location was, between `0` and `1`
+    pub score: f32,
+}
+
+/// Road between two towns
+#[derive(Clone, Debug)]
+pub struct Road {
+    /// cells the road passes through in order
+    pub path: Vec<(usize, usize)>,
+    /// whether the road crosses a river on a bridge, for each cell of the path
+    pub bridges: Vec<bool>,
+}
+
+/// Parameters of the road network
+#[derive(Copy, Clone, Debug)]
+pub struct RoadCosts {
+    /// additional cost of a cell with a slope of 10 degrees, growing quadratically with the slope
+    pub slope: f32,
+    /// additional cost of crossing a river cell, which needs a bridge
+    pub bridge: f32,
+    /// factor of the cost of cells on existing roads, so that roads join instead of running
+    /// side by side
+    pub reuse: f32,
+}
+
+impl Default for RoadCosts {
+    fn default() -> Self {
+        Self {
+            slope: 1.0,
+            bridge: 25.0,
+            reuse: 0.5,
+        }
+    }
+}
+
+/// Rules rating how well suited a location is for a town
+struct TownRules {
+    flat: Rule,
+    fertile: Rule,
+    river: Rule,
+    coast: Rule,
+}
+
+impl TownRules {
+    fn new() -> Self {
+        Self {
+            flat: Rule::Slope {
+                min: 0.0,
+                max: 5.0,
+                blend: 10.0,
+            },
+            fertile: Rule::Band {
+                from: Terrain::Sand,
+                to: Terrain::Grass,
+                blend: 1.0,
+            },
+            river: Rule::RiverDistance {
+                min: 0.0,
+                max: 2.0,
+                blend: 6.0,
+            },
+            coast: Rule::LakeDistance {
+                min: 1.0,
+                max: 3.0,
+                blend: 6.0,
+            },
+        }
+    }
+
+    fn rules(&self) -> [&Rule; 4] {
+        [&self.flat, &self.fertile, &self.river, &self.coast]
+    }
+
+    /// Towns like flat fertile land, and even more so next to rivers, lake shores and especially
+    /// river mouths.
+    fn score(&self, input: &SplatInput, maps: &RuleMaps, xy: (usize, usize)) -> f32 {
+        let [flat, fertile, river, coast] = self.rules().map(|r| r.factor(input, maps, xy));
+        let water = 0.2 + 0.3 * river + 0.5 * coast + river * coast;
+        flat * fertile * water / 2.0
+    }
+}
+
+/// Places up to `count` towns on the best suited locations, keeping at least `spacing` cells
+/// between them.
+pub fn place_towns(input: &SplatInput, count: usize, spacing: f32) -> Vec<Town> {
+    let rules = TownRules::new();
+    let maps = RuleMaps::new(input, rules.rules().iter().cloned());
+
+    let mut score = Map::new(input.terrain.width(), input.terrain.height());
+    score.map_coords(|x, y, _| {
+        if input.lake[(x, y)] > 0.0 {
+            0.0
+        } else {
+            rules.score(input, &maps, (x, y))
+        }
+    });
+
+    let mut candidates = Vec::new();
+    for x in 0..score.width() {
+        for y in 0..score.height() {
+            if score[(x, y)] > 0.0 {
+                candidates.push(Town {
+                    x,
+                    y,
+                    score: score[(x, y)],
+                });
+            }
+        }
+    }
+    candidates.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
+
+    let mut towns: Vec<Town> = Vec::new();
+    for candidate in candidates.into_iter() {
+        if towns.len() >= count {
+            break;
+        }
+        let far_enough = towns.iter().all(|t| {
+            let (dx, dy) = (
+                t.x as f32 - candidate.x as f32,
+                t.y as f32 - candidate.y as f32,
+            );
+            dx.hypot(dy) >= spacing
+        });
+        if far_enough {
+            towns.push(candidate);
+        }
+    }
+
+    towns
+}
+
+/// Cost of building a road through each cell. Water can't be crossed except for rivers, which
+/// are expensive because they need a bridge.
+pub fn road_cost_map(input: &SplatInput, costs: &RoadCosts) -> Map {
+    let slope = input.terrain.slope();
+    let mut cost = Map::new(input.terrain.width(), input.terrain.height());
+    cost.map_coords(|x, y, _| {
+        let xy = (x, y);
+        if input.lake[xy] > 0.0 {
+            return f32::INFINITY;
+        }
+        let steepness = slope[xy] / 10.0;
+        let bridge = if input.river[xy] > input.river_level {
+            costs.bridge
+        } else {
+            0.0
+        };
+        1.0 + costs.slope * steepness * steepness + bridge
+    });
+    cost
+}
+
+/// Finds the cheapest 8-connected path between two cells with A*.
+///
+/// The cost of a step is its length times the average cost of both cells. All costs need to be
+/// at least `1`, cells with an infinite cost are never entered.
+pub fn find_path(
+    cost: &Map,
+    from: (usize, usize),
+    to: (usize, usize),
+) -> Option<Vec<(usize, usize)>> {
+    let (width, height) = (cost.width(), cost.height());
+    let heuristic = |x: usize, y: usize| (x as f32 - to.0 as f32).hypot(y as f32 - to.1 as f32);
+
+    let mut dist = Grid::filled(width, height, f32::INFINITY);
+    let mut parent = Grid::filled(width, height, usize::MAX);
+    let mut queue = BinaryHeap::new();
+
+    dist[from] = 0.0;
+    queue.push(Point {
+        x: from.0,
+        y: from.1,
+        z: heuristic(from.0, from.1),
+    });
+
+    while let Some(Point { x, y, z }) = queue.pop() {
+        if (x, y) == to {
+            let mut path = vec![to];
+            let mut xy = to;
+            while parent[xy] != usize::MAX {
+                xy = cost.unflatten(parent[xy]);
+                path.push(xy);
+            }
+            path.reverse();
+            return Some(path);
+        }
+
+        if z > dist[(x, y)] + heuristic(x, y) {
+            // a shorter way to this cell has been found after it was queued
+            continue;
+        }
+
+        for (nx, ny) in cost.neighbors8(x, y, false) {
+            if cost[(nx, ny)].is_infinite() {
+                continue;
+            }
+
+            let length = if nx != x && ny != y { SQRT_2 } else { 1.0 };
+            let step = length * 0.5 * (cost[(x, y)] + cost[(nx, ny)]);
+            let nd = dist[(x, y)] + step;
+
+            if nd < dist[(nx, ny)] {
+                dist[(nx, ny)] = nd;
+                parent[(nx, ny)] = cost.flatten_xy(x, y);
+                queue.push(Point {
+                    x: nx,
+                    y: ny,
+                    z: nd + heuristic(nx, ny),
+                });
+            }
+        }
+    }
+
+    None
+}
+
+/// Connects the towns with roads along a minimum spanning tree.
+///
+/// Roads are built from the shortest connection to the longest, each one making the cells it
+/// uses cheaper for the following ones.
+pub fn build_roads(input: &SplatInput, towns: &[Town], costs: &RoadCosts) -> Vec<Road> {
+    let mut cost = road_cost_map(input, costs);
+    let mut roads = Vec::new();
+
+    for (a, b) in spanning_tree(towns).into_iter() {
+        let from = (towns[a].x, towns[a].y);
+        let to = (towns[b].x, towns[b].y);
+
+        if let Some(path) = find_path(&cost, from, to) {
+            for &xy in path.iter() {
+                cost[xy] = 1.0 + (cost[xy] - 1.0) * costs.reuse;
+            }
+            let bridges = path
+                .iter()
+                .map(|&xy| input.river[xy] > input.river_level)
+                .collect();
+            roads.push(Road { path, bridges });
+        }
+    }
+
+    roads
+}
+
+/// Edges of the euclidean minimum spanning tree of the towns, sorted by length
+fn spanning_tree(towns: &[Town]) -> Vec<(usize, usize)> {
+    let dist = |a: &Town, b: &Town| (a.x as f32 - b.x as f32).hypot(a.y as f32 - b.y as f32);
+
+    if towns.is_empty() {
+        return Vec::new();
+    }
+
+    let mut edges = Vec::new();
+    let mut connected = vec![false; towns.len()];
+    // closest connected town and its distance for every unconnected town
+    let mut closest = vec![(0, f32::INFINITY); towns.len()];
+
+    connected[0] = true;
+    for (i, town) in towns.iter().enumerate().skip(1) {
+        closest[i] = (0, dist(&towns[0], town));
+    }
+
+    for _ in 1..towns.len() {
+        let next = (0..towns.len())
+            .filter(|&i| !connected[i])
+            .min_by(|&a, &b| closest[a].1.partial_cmp(&closest[b].1).unwrap())
+            .unwrap();
+
+        connected[next] = true;
+        edges.push((closest[next].0, next, closest[next].1));
+
+        for i in 0..towns.len() {
+            let d = dist(&towns[next], &towns[i]);
+            if !connected[i] && d < closest[i].1 {
+                closest[i] = (next, d);
+            }
+        }
+    }
+
+    edges.sort_by(|a, b| a.2.partial_cmp(&b.2).unwrap());
+    edges.into_iter().map(|(a, b, _)| (a, b)).collect()
+}
+
+/// Rasterizes the roads into a map, where road cells are `1` and bridges `2`
+pub fn road_map(roads: &[Road], width: usize, height: usize) -> Map {
+    let mut map = Map::new(width, height);
+    for road in roads.iter() {
+        for (w, bridge) in road.path.windows(2).zip(road.bridges.iter()) {
+            let ((x1, y1), (x2, y2)) = (w[0], w[1]);
+            let value = if *bridge { 2.0 } else { 1.0 };
+            draw_line(&mut map, x1 as _, y1 as _, x2 as _, y2 as _, |h| {
+                h.max(value)
+            });
+        }
+    }
+    map
+}
+
+/// Writes each road as a line on the terrain surface
+#[allow(unused)]
+pub fn export_roads<W: Write>(roads: &[Road], terrain: &Map, obj: &mut ObjWriter<W>) -> Result<()> {
+    for road in roads.iter() {
+        let vertices: Vec<_> = road
+            .path
+            .iter()
+            .map(|&(x, y)| Vector::new(x as _, y as _, terrain[(x, y)]))
+            .collect();
+        obj.line(&vertices)?;
+    }
+    Ok(())
+}
+
+/// Roads and towns for the overlay of the renderer
+pub fn overlay(towns: &[Town], roads: &[Road]) -> Overlay {
+    let lines = roads
+        .iter()
+        .map(|road| Polyline {
+            points: road
+                .path
+                .iter()
+                .map(|&(x, y)| (x as f32, y as f32))
+                .collect(),
+            width: 2.0,
+            color: [120, 70, 40],
+        })
+        .collect();
+    let markers = towns
+        .iter()
+        .map(|town| Marker {
+            x: town.x as f32,
+            y: town.y as f32,
+            radius: 4.0,
+            color: [200, 40, 40],
+        })
+        .collect();
+    Overlay { lines, markers }
+}
+
+/// Check that roads go around lakes and only cross rivers where they have to
+#[test]
+fn test_road_network() {
+    let size = 64;
+    let mut terrain = Map::new(size, size);
+    terrain.map(|_| 30.0);
+    let mut lake = Map::new(size, size);
+    let mut river = Map::new(size, size);
+    for y in 10..54 {
+        lake[(32, y)] = 1.0;
+    }
+    for x in 0..size {
+        river[(x, 58)] = 1000.0;
+    }
+
+    let input = SplatInput {
+        terrain: &terrain,
+        river: &river,
+        lake: &lake,
+        bands: Bands::new(20.0, 100.0),
+        river_level: 100.0,
+    };
+    let town = |x, y| Town { x, y, score: 1.0 };
+    let towns = [town(20, 32), town(40, 32), town(20, 62)];
+    let roads = build_roads(&input, &towns, &RoadCosts::default());
+    assert_eq!(roads.len(), 2);
+
+    // the shortest connection is built first, around the lake
+    let road = &roads[0];
+    assert_eq!(road.path.first(), Some(&(20, 32)));
+    assert_eq!(road.path.last(), Some(&(40, 32)));
+    assert!(road.path.iter().all(|&xy| lake[xy] == 0.0));
+    assert!(road.bridges.iter().all(|b| !b));
+
+    // the third town is across the river
+    assert_eq!(roads[1].bridges.iter().filter(|&&b| b).count(), 1);
+}
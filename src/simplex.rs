@@ -1,17 +1,151 @@
 use crate::map::Map;
+use rand::prelude::*;
+use std::f32::consts::PI;
+
+/// Named kind of island, given by the shape of its relief and the noise on top of it
+#[derive(Copy, Clone, Debug)]
+pub struct Preset {
+    pub name: &'static str,
+    pub description: &'static str,
+    /// relief of the island at normalized coordinates, where `-1` and `1` are the edges of the
+    /// map, so the island is stretched along with the map
+    pub shape: fn(f32, f32) -> f32,
+    /// the noise, between `-1` and `1`, is scaled by this and added to `noise_base` before it is
+    /// multiplied with the shape
+    pub noise_amplitude: f32,
+    pub noise_base: f32,
+    /// frequency of the noise, in features along the shorter side of the map
+    pub noise_scale: f32,
+}
+
+/// All the available presets, the first one being the default
+pub const PRESETS: [Preset; 5] = [
+    Preset {
+        name: "default",
+        description: "a single island with a mountain in the middle",
+        shape: central_mountain,
+        noise_amplitude: 16.0,
+        noise_base: 17.0,
+        noise_scale: 5.02,
+    },
+    Preset {
+        name: "volcano",
+        description: "volcanic cone with a caldera holding a crater lake",
+        shape: volcano,
+        noise_amplitude: 5.0,
+        noise_base: 17.0,
+        noise_scale: 6.0,
+    },
+    Preset {
+        name: "atoll",
+        description: "ring of reef islands around a shallow lagoon",
+        shape: atoll,
+        noise_amplitude: 7.0,
+        noise_base: 17.0,
+        noise_scale: 9.0,
+    },
+    Preset {
+        name: "fault-block",
+        description: "tilted block with a gentle slope and a steep fault scarp",
+        shape: fault_block,
+        noise_amplitude: 8.0,
+        noise_base: 17.0,
+        noise_scale: 5.02,
+    },
+    Preset {
+        name: "chain",
+        description: "elongated chain of islets",
+        shape: islet_chain,
+        noise_amplitude: 10.0,
+        noise_base: 17.0,
+        noise_scale: 7.0,
+    },
+];
+
+/// Looks up a preset by its name
+pub fn preset(name: &str) -> Option<Preset> {
+    PRESETS.iter().find(|p| p.name == name).cloned()
+}
+
+fn central_mountain(u: f32, v: f32) -> f32 {
+    let (u, v) = (2.25 * u, 2.25 * v);
+    6.0 / (u * u + v * v + 1.0)
+}
+
+fn volcano(u: f32, v: f32) -> f32 {
+    let cone = |r: f32| 7.0 * (1.0 - r).max(0.0).powf(1.6);
+    let caldera = 0.22;
+    let r = u.hypot(v);
+    if r < caldera {
+        // bowl sunk into the top of the cone, deep enough to hold a lake
+        let t = r / caldera;
+        cone(caldera) * (1.0 - 0.45 * (1.0 - t * t))
+    } else {
+        cone(r)
+    }
+}
+
+fn atoll(u: f32, v: f32) -> f32 {
+    let r = u.hypot(v);
+    let reef = (r - 0.6) / 0.08;
+    let ring = 1.6 * (-reef * reef).exp();
+    if r < 0.6 {
+        // shallow lagoon floor, just below the ocean
+        ring.max(0.9)
+    } else {
+        ring
+    }
+}
+
+fn fault_block(u: f32, v: f32) -> f32 {
+    let angle: f32 = 0.3;
+    // position across the fault, and along it
+    let t = u * angle.cos() + v * angle.sin();
+    let w = v * angle.cos() - u * angle.sin();
+
+    let scarp = 0.45;
+    let profile = if t < scarp {
+        5.0 * ((t + 0.9) / (scarp + 0.9)).max(0.0).powf(1.2)
+    } else {
+        5.0 * (1.0 - (t - scarp) / 0.12).max(0.0)
+    };
+    let lateral = (1.0 - (w.abs() / 0.85).powi(4)).max(0.0);
+    profile * lateral
+}
+
+fn islet_chain(u: f32, v: f32) -> f32 {
+    let count = 7;
+    (0..count)
+        .map(|i| {
+            let s = i as f32 / (count - 1) as f32;
+            let (cu, cv) = (-0.8 + 1.6 * s, 0.25 * (PI * s).sin() - 0.12);
+            let height = 2.4 + 1.2 * (2.3 * i as f32).sin();
+            let radius = 0.09 + 0.04 * (1.7 * i as f32).cos().abs();
+            let d = (u - cu).hypot(v - cv) / radius;
+            height * (-d * d).exp()
+        })
+        .fold(0.0, f32::max)
+}
 
 /// returns a simplex based height map with a central raise and edges scaled down
+#[allow(unused)]
 pub fn simplex_map(width: usize, height: usize) -> Map {
+    preset_map(width, height, &PRESETS[0], 0)
+}
+
+/// returns a simplex based height map shaped by the preset, with the edges scaled down. The same
+/// `seed` always gives the same map.
+pub fn preset_map(width: usize, height: usize, preset: &Preset, seed: u64) -> Map {
     // simplex noise parameters
     let iter = 4;
     let persistence = 0.3;
-    let scale = 5.02 / width as f32;
+    let scale = preset.noise_scale / width.min(height) as f32;
 
     // power to give edge scaling a shape
     // a value of 2.0 would give the edge the shape of a circle
     let edge_pow = 9.0;
 
-    let simplex = fuss::Simplex::new();
+    let simplex = fuss::Simplex::from_seed(vec![seed as usize]);
     let mut map = Map::new(width, height);
 
     map.map_coords(|x, y, _| {
@@ -20,32 +154,244 @@ pub fn simplex_map(width: usize, height: usize) -> Map {
 
             let (dx, dy) = (x - width * 0.5, y - height * 0.5);
 
+            // normalized coordinates
+            let (u, v) = (dx / (0.5 * width), dy / (0.5 * height));
+
             let edge_scaling = {
-                let dist = dx.abs().powf(edge_pow) + dy.abs().powf(edge_pow);
+                let dist = u.abs().powf(edge_pow) + v.abs().powf(edge_pow);
 
-                let fade0 = (0.5 * width.min(height)).powf(edge_pow);
+                let fade0 = 1.0;
                 let fade1 = (0.8f32).powf(edge_pow) * fade0;
 
                 let v = (dist - fade0) / (fade1 - fade0);
-                let v = v.max(0.0).min(1.0);
+                let v = v.clamp(0.0, 1.0);
 
                 // sin curve mapping 0,1 to 0,1 to have a smooth gradient
-                (((v - 0.5) * std::f32::consts::PI).sin() + 1.0) * 0.5
-            };
-
-            let mountain_scaling = {
-                let scale = 4.5 / width;
-                let (dx, dy) = (dx * scale, dy * scale);
-                6.0 / (dx * dx + dy * dy + 1.0)
+                (((v - 0.5) * PI).sin() + 1.0) * 0.5
             };
 
-            edge_scaling * mountain_scaling
+            edge_scaling * (preset.shape)(u, v)
         };
 
         let height = simplex.sum_octave_2d(iter, x as _, y as _, persistence, scale);
-        let scaled_height = height_scaling * (16.0 * height + 17.0);
-        scaled_height
+        height_scaling * (preset.noise_amplitude * height + preset.noise_base)
     });
 
     map
 }
+
+/// relief of tileable maps, which have no island shape
+const TILE_RELIEF: f32 = 1.6;
+
+/// returns a simplex based height map which tiles seamlessly.
+///
+/// The noise is sampled on a torus in four dimensional noise space, so that it is periodic in
+/// both directions. The shape of the preset and the edge scaling are left out, as they don't
+/// repeat, only its noise parameters are used. The same `seed` always gives the same map.
+pub fn tileable_map(width: usize, height: usize, preset: &Preset, seed: u64) -> Map {
+    let iter = 4;
+    let persistence = 0.3;
+    let scale = preset.noise_scale / width.min(height) as f32;
+
+    // radii of the torus, so that the features have the same size as on the other maps
+    let rx = width as f32 * scale / (2.0 * PI);
+    let ry = height as f32 * scale / (2.0 * PI);
+
+    let noise = TorusNoise::new(&mut StdRng::seed_from_u64(seed));
+    let mut map = Map::new(width, height);
+
+    map.map_coords(|x, y, _| {
+        let a = 2.0 * PI * x as f32 / width as f32;
+        let b = 2.0 * PI * y as f32 / height as f32;
+        let point = [rx * a.cos(), rx * a.sin(), ry * b.cos(), ry * b.sin()];
+
+        let mut sum = 0.0;
+        let mut amplitude = 1.0;
+        let mut max_amplitude = 0.0;
+        let mut frequency = 1.0;
+        for _ in 0..iter {
+            let [p, q, r, s] = point;
+            sum += amplitude
+                * noise.noise_4d(p * frequency, q * frequency, r * frequency, s * frequency);
+            max_amplitude += amplitude;
+            amplitude *= persistence;
+            frequency *= 2.0;
+        }
+
+        let height = sum / max_amplitude;
+        TILE_RELIEF * (preset.noise_amplitude * height + preset.noise_base)
+    });
+
+    map
+}
+
+/// Four dimensional simplex noise, after Stefan Gustavson's "Simplex noise demystified"
+struct TorusNoise {
+    perm: [u8; 512],
+}
+
+impl TorusNoise {
+    fn new(rng: &mut impl Rng) -> Self {
+        let mut p: Vec<u8> = (0..=255).collect();
+        p.shuffle(rng);
+        let mut perm = [0; 512];
+        for (i, v) in perm.iter_mut().enumerate() {
+            *v = p[i & 255];
+        }
+        Self { perm }
+    }
+
+    /// noise value between about `-1` and `1`
+    fn noise_4d(&self, x: f32, y: f32, z: f32, w: f32) -> f32 {
+        const GRAD: [[f32; 4]; 32] = [
+            [0.0, 1.0, 1.0, 1.0],
+            [0.0, 1.0, 1.0, -1.0],
+            [0.0, 1.0, -1.0, 1.0],
+            [0.0, 1.0, -1.0, -1.0],
+            [0.0, -1.0, 1.0, 1.0],
+            [0.0, -1.0, 1.0, -1.0],
+            [0.0, -1.0, -1.0, 1.0],
+            [0.0, -1.0, -1.0, -1.0],
+            [1.0, 0.0, 1.0, 1.0],
+            [1.0, 0.0, 1.0, -1.0],
+            [1.0, 0.0, -1.0, 1.0],
+            [1.0, 0.0, -1.0, -1.0],
+            [-1.0, 0.0, 1.0, 1.0],
+            [-1.0, 0.0, 1.0, -1.0],
+            [-1.0, 0.0, -1.0, 1.0],
+            [-1.0, 0.0, -1.0, -1.0],
+            [1.0, 1.0, 0.0, 1.0],
+            [1.0, 1.0, 0.0, -1.0],
+            [1.0, -1.0, 0.0, 1.0],
+            [1.0, -1.0, 0.0, -1.0],
+            [-1.0, 1.0, 0.0, 1.0],
+            [-1.0, 1.0, 0.0, -1.0],
+            [-1.0, -1.0, 0.0, 1.0],
+            [-1.0, -1.0, 0.0, -1.0],
+            [1.0, 1.0, 1.0, 0.0],
+            [1.0, 1.0, -1.0, 0.0],
+            [1.0, -1.0, 1.0, 0.0],
+            [1.0, -1.0, -1.0, 0.0],
+            [-1.0, 1.0, 1.0, 0.0],
+            [-1.0, 1.0, -1.0, 0.0],
+            [-1.0, -1.0, 1.0, 0.0],
+            [-1.0, -1.0, -1.0, 0.0],
+        ];
+        let sqrt5 = 5.0f32.sqrt();
+        let f4 = (sqrt5 - 1.0) / 4.0;
+        let g4 = (5.0 - sqrt5) / 20.0;
+
+        // skew the input space to find the simplex cell
+        let s = (x + y + z + w) * f4;
+        let cell = [
+            (x + s).floor(),
+            (y + s).floor(),
+            (z + s).floor(),
+            (w + s).floor(),
+        ];
+        let t = cell.iter().sum::<f32>() * g4;
+        let p0 = [
+            x - (cell[0] - t),
+            y - (cell[1] - t),
+            z - (cell[2] - t),
+            w - (cell[3] - t),
+        ];
+
+        // rank the coordinates to find out which simplex of the cell the point is in
+        let mut rank = [0; 4];
+        for i in 0..4 {
+            for j in i + 1..4 {
+                if p0[i] > p0[j] {
+                    rank[i] += 1;
+                } else {
+                    rank[j] += 1;
+                }
+            }
+        }
+
+        let perm = |v: usize| self.perm[v] as usize;
+        let (i, j, k, l) = (
+            cell[0] as i32 as usize & 255,
+            cell[1] as i32 as usize & 255,
+            cell[2] as i32 as usize & 255,
+            cell[3] as i32 as usize & 255,
+        );
+
+        let mut sum = 0.0;
+        for corner in 0..5 {
+            // offset of the corner in the unskewed cell
+            let mut o = [0; 4];
+            for (o, &r) in o.iter_mut().zip(rank.iter()) {
+                *o = (corner > 0 && r >= 4 - corner) as usize;
+            }
+            let c = corner as f32 * g4;
+            let d = [
+                p0[0] - o[0] as f32 + c,
+                p0[1] - o[1] as f32 + c,
+                p0[2] - o[2] as f32 + c,
+                p0[3] - o[3] as f32 + c,
+            ];
+
+            let t = 0.6 - d.iter().map(|v| v * v).sum::<f32>();
+            if t > 0.0 {
+                let g = perm(i + o[0] + perm(j + o[1] + perm(k + o[2] + perm(l + o[3])))) % 32;
+                let dot: f32 = GRAD[g].iter().zip(d.iter()).map(|(a, b)| a * b).sum();
+                sum += t * t * t * t * dot;
+            }
+        }
+
+        27.0 * sum
+    }
+}
+
+/// Check that every preset has land in it and ocean at the edges of the map, also when the map
+/// isn't square, and that the seed selects the map
+#[test]
+fn test_presets() {
+    let ocean = 20.0;
+    for &(width, height) in [(128, 128), (200, 72)].iter() {
+        for preset in PRESETS.iter() {
+            let map = preset_map(width, height, preset, 0);
+            let (_, max) = map.minmax();
+            assert!(max > ocean, "{} has no land", preset.name);
+
+            let edges = (0..width)
+                .flat_map(|x| vec![(x, 0), (x, height - 1)])
+                .chain((0..height).flat_map(|y| vec![(0, y), (width - 1, y)]));
+            for xy in edges {
+                assert!(map[xy] < ocean, "{} has land at the edge", preset.name);
+            }
+        }
+    }
+
+    let map = |seed| preset_map(64, 48, &PRESETS[0], seed);
+    assert_eq!(map(7).values(), map(7).values());
+    assert_ne!(map(7).values(), map(8).values());
+}
+
+/// Check that tileable maps are as smooth across their edges as inside, and only depend on their
+/// seed
+#[test]
+fn test_tileable_edges() {
+    let (width, height) = (96, 64);
+    let map = tileable_map(width, height, &PRESETS[0], 3);
+    let same = tileable_map(width, height, &PRESETS[0], 3);
+    let other = tileable_map(width, height, &PRESETS[0], 4);
+    assert_eq!(map.values(), same.values());
+    assert_ne!(map.values(), other.values());
+
+    let mut steepest = 0.0f32;
+    for x in 0..width - 1 {
+        for y in 0..height - 1 {
+            steepest = steepest.max((map[(x, y)] - map[(x + 1, y)]).abs());
+            steepest = steepest.max((map[(x, y)] - map[(x, y + 1)]).abs());
+        }
+    }
+
+    for y in 0..height {
+        assert!((map[(0, y)] - map[(width - 1, y)]).abs() <= steepest);
+    }
+    for x in 0..width {
+        assert!((map[(x, 0)] - map[(x, height - 1)]).abs() <= steepest);
+    }
+}
@@ -0,0 +1,242 @@
+use std::fmt::Debug;
+use std::sync::Arc;
+
+/// How steep the terrain is at a cell, given the value of the river map there.
+///
+/// Small streams make for steep slopes and large rivers for flat valleys, how fast the slope falls
+/// off with the size of the river decides between broad valleys and sharp canyons.
+pub trait SlopeModel: Send + Sync + Debug {
+    /// height the terrain rises per cell, which must not be negative
+    fn slope(&self, river: f32) -> f32;
+}
+
+/// Slope of `scale / (offset + river)`, which is the default
+#[derive(Copy, Clone, Debug)]
+pub struct InverseSlope {
+    pub scale: f32,
+    /// keeps the slope finite where there is no water, the smaller it is the steeper the terrain
+    /// away from rivers
+    pub offset: f32,
+}
+
+impl Default for InverseSlope {
+    fn default() -> Self {
+        Self {
+            scale: 1.0,
+            offset: 0.5,
+        }
+    }
+}
+
+impl SlopeModel for InverseSlope {
+    fn slope(&self, river: f32) -> f32 {
+        self.scale / (self.offset + river)
+    }
+}
+
+/// Slope of `k * river^-m` like in the stream power law, river values below `1` count as `1`.
+///
+/// The larger `m`, the faster the terrain flattens out along rivers, which carves sharp canyons.
+#[derive(Copy, Clone, Debug)]
+pub struct PowerLawSlope {
+    pub k: f32,
+    pub m: f32,
+}
+
+impl Default for PowerLawSlope {
+    fn default() -> Self {
+        Self { k: 1.0, m: 0.5 }
+    }
+}
+
+impl SlopeModel for PowerLawSlope {
+    fn slope(&self, river: f32) -> f32 {
+        self.k * river.max(1.0).powf(-self.m)
+    }
+}
+
+/// Slope which falls from `steep` for small streams to `flat` for large rivers along a logistic
+/// curve, which gives broad valleys with a sharp edge
+#[derive(Copy, Clone, Debug)]
+pub struct LogisticSlope {
+    /// slope where there is no water
+    pub steep: f32,
+    /// slope along the largest rivers
+    pub flat: f32,
+    /// river value at which the slope is halfway between `steep` and `flat`
+    pub midpoint: f32,
+    /// how fast the slope changes around the midpoint
+    pub rate: f32,
+}
+
+impl Default for LogisticSlope {
+    fn default() -> Self {
+        Self {
+            steep: 0.6,
+            flat: 0.02,
+            midpoint: 10.0,
+            rate: 0.5,
+        }
+    }
+}
+
+impl SlopeModel for LogisticSlope {
+    fn slope(&self, river: f32) -> f32 {
+        let t = 1.0 / (1.0 + (-self.rate * (river - self.midpoint)).exp());
+        self.steep + (self.flat - self.steep) * t
+    }
+}
+
+/// Slope interpolated linearly between `(river, slope)` points, and constant beyond the first and
+/// last one
+#[derive(Clone, Debug)]
+pub struct CurveSlope {
+    points: Vec<(f32, f32)>,
+}
+
+impl CurveSlope {
+    pub fn new(mut points: Vec<(f32, f32)>) -> Self {
+        assert!(!points.is_empty(), "slope curve without any points");
+        points.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Self { points }
+    }
+}
+
+impl SlopeModel for CurveSlope {
+    fn slope(&self, river: f32) -> f32 {
+        let idx = self.points.partition_point(|p| p.0 <= river);
+        if idx == 0 {
+            return self.points[0].1;
+        }
+        if idx == self.points.len() {
+            return self.points[idx - 1].1;
+        }
+        let (x0, y0) = self.points[idx - 1];
+        let (x1, y1) = self.points[idx];
+        y0 + (y1 - y0) * (river - x0) / (x1 - x0)
+    }
+}
+
+/// Slope model from a description like `power-law:k=2,m=0.6`, which is the name of the model
+/// followed by the values of some of its parameters, the others keep their defaults.
+///
+/// The models are `inverse`, `power-law` and `logistic`, with the parameters named like the fields
+/// of their structs, and `curve` with its points as `river=slope`, like `curve:0=1,10=0.3`. All
+/// numbers have to be finite.
+pub fn parse_model(description: &str) -> Result<Arc<dyn SlopeModel>, String> {
+    let (name, params) = description.split_once(':').unwrap_or((description, ""));
+    let params = params
+        .split(',')
+        .filter(|param| !param.trim().is_empty())
+        .map(|param| {
+            let (key, value) = param
+                .split_once('=')
+                .ok_or_else(|| format!("parameter {} without a value", param))?;
+            let value = value
+                .trim()
+                .parse::<f32>()
+                .ok()
+                .filter(|value| value.is_finite())
+                .ok_or_else(|| format!("invalid value of {}: {}", key, value))?;
+            Ok((key.trim(), value))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    let unknown = |key: &str| format!("slope model {} has no parameter {}", name, key);
+
+    match name {
+        "inverse" => {
+            let mut model = InverseSlope::default();
+            for (key, value) in params {
+                match key {
+                    "scale" => model.scale = value,
+                    "offset" => model.offset = value,
+                    _ => return Err(unknown(key)),
+                }
+            }
+            Ok(Arc::new(model))
+        }
+        "power-law" => {
+            let mut model = PowerLawSlope::default();
+            for (key, value) in params {
+                match key {
+                    "k" => model.k = value,
+                    "m" => model.m = value,
+                    _ => return Err(unknown(key)),
+                }
+            }
+            Ok(Arc::new(model))
+        }
+        "logistic" => {
+            let mut model = LogisticSlope::default();
+            for (key, value) in params {
+                match key {
+                    "steep" => model.steep = value,
+                    "flat" => model.flat = value,
+                    "midpoint" => model.midpoint = value,
+                    "rate" => model.rate = value,
+                    _ => return Err(unknown(key)),
+                }
+            }
+            Ok(Arc::new(model))
+        }
+        "curve" => {
+            let points = params
+                .into_iter()
+                .map(|(key, value)| {
+                    key.parse::<f32>()
+                        .ok()
+                        .filter(|river| river.is_finite())
+                        .map(|river| (river, value))
+                })
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| "points of a slope curve are given as river=slope".to_string())?;
+            if points.is_empty() {
+                return Err("slope curve without any points".to_string());
+            }
+            Ok(Arc::new(CurveSlope::new(points)))
+        }
+        _ => Err(format!(
+            "unknown slope model {}, use one of inverse, power-law, logistic, curve",
+            name
+        )),
+    }
+}
+
+/// Check the slopes of the different models at a few river values
+#[test]
+fn test_slope_models() {
+    let close = |a: f32, b: f32| (a - b).abs() < 1e-5;
+
+    let inverse = InverseSlope::default();
+    assert!(close(inverse.slope(1.0), 1.0 / 1.5));
+    assert!(close(inverse.slope(0.0), 2.0));
+
+    let power = PowerLawSlope { k: 2.0, m: 0.5 };
+    assert!(close(power.slope(4.0), 1.0));
+    assert!(close(power.slope(0.0), 2.0));
+
+    let logistic = LogisticSlope::default();
+    assert!(close(logistic.slope(logistic.midpoint), 0.5 * (0.6 + 0.02)));
+    assert!(logistic.slope(0.0) > 0.59 && logistic.slope(0.0) < 0.6);
+    assert!(logistic.slope(100.0) < 0.021);
+
+    let curve = CurveSlope::new(vec![(10.0, 0.1), (0.0, 1.0), (2.0, 0.5)]);
+    assert!(close(curve.slope(-1.0), 1.0));
+    assert!(close(curve.slope(1.0), 0.75));
+    assert!(close(curve.slope(2.0), 0.5));
+    assert!(close(curve.slope(6.0), 0.3));
+    assert!(close(curve.slope(20.0), 0.1));
+
+    let parsed = parse_model("power-law:k=2, m=0.5").unwrap();
+    assert!(close(parsed.slope(4.0), 1.0));
+    let parsed = parse_model("curve:10=0.1,0=1,2=0.5").unwrap();
+    assert!(close(parsed.slope(6.0), 0.3));
+    assert!(close(parse_model("inverse").unwrap().slope(1.0), 1.0 / 1.5));
+    assert!(parse_model("logistic:steepness=1").is_err());
+    assert!(parse_model("power-law:k").is_err());
+    assert!(parse_model("curve").is_err());
+    assert!(parse_model("cliff").is_err());
+    assert!(parse_model("curve:nan=1").is_err());
+    assert!(parse_model("curve:0=1,inf=2").is_err());
+    assert!(parse_model("power-law:k=inf").is_err());
+}
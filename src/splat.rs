@@ -0,0 +1,285 @@
+use crate::{map::*, vis::*};
+use std::io::{Result, Write};
+
+/// Condition on where a material is placed.
+///
+/// Each rule gives a factor between `0` and `1` for every cell. Outside of its range the factor
+/// falls off linearly to `0` over the distance `blend`, so materials fade into each other.
+#[derive(Copy, Clone, Debug)]
+pub enum Rule {
+    /// height within the bands of the terrain kinds `from..=to`, as used by the renderer
+    Band {
+        from: Terrain,
+        to: Terrain,
+        blend: f32,
+    },
+    /// height within `min..max`
+    #[allow(unused)]
+    Height { min: f32, max: f32, blend: f32 },
+    /// slope in degrees within `min..max`
+    Slope { min: f32, max: f32, blend: f32 },
+    /// value of the river map within `min..max`
+    Wetness { min: f32, max: f32, blend: f32 },
+    /// distance in cells to the closest lake or sea cell within `min..max`
+    LakeDistance { min: f32, max: f32, blend: f32 },
+    /// distance in cells to the closest river within `min..max`
+    RiverDistance { min: f32, max: f32, blend: f32 },
+}
+
+impl Rule {
+    /// Factor of the rule at the position `xy`
+    pub fn factor(&self, input: &SplatInput, maps: &RuleMaps, xy: (usize, usize)) -> f32 {
+        let derived = |map: &Option<Map>| map.as_ref().expect("derived map missing")[xy];
+        match *self {
+            Rule::Band { from, to, blend } => {
+                let (min, _) = input.bands.range(from);
+                let (_, max) = input.bands.range(to);
+                ramp(input.terrain[xy], min, max, blend)
+            }
+            Rule::Height { min, max, blend } => ramp(input.terrain[xy], min, max, blend),
+            Rule::Slope { min, max, blend } => ramp(derived(&maps.slope), min, max, blend),
+            Rule::Wetness { min, max, blend } => ramp(input.river[xy], min, max, blend),
+            Rule::LakeDistance { min, max, blend } => {
+                ramp(derived(&maps.lake_distance), min, max, blend)
+            }
+            Rule::RiverDistance { min, max, blend } => {
+                ramp(derived(&maps.river_distance), min, max, blend)
+            }
+        }
+    }
+}
+
+/// Maps derived from the terrain which are needed by some of the rules.
+///
+/// Each of them is only computed if a rule uses it.
+pub struct RuleMaps {
+    slope: Option<Map>,
+    lake_distance: Option<Map>,
+    river_distance: Option<Map>,
+}
+
+impl RuleMaps {
+    pub fn new<'r>(input: &SplatInput, rules: impl Iterator<Item = &'r Rule> + Clone) -> Self {
+        let uses = |f: fn(&Rule) -> bool| rules.clone().any(f);
+        let river_level = input.river_level;
+
+        Self {
+            slope: if uses(|r| matches!(r, Rule::Slope { .. })) {
+                Some(input.terrain.slope())
+            } else {
+                None
+            },
+            lake_distance: if uses(|r| matches!(r, Rule::LakeDistance { .. })) {
+                Some(input.lake.distance_to(|l| l > 0.0))
+            } else {
+                None
+            },
+            river_distance: if uses(|r| matches!(r, Rule::RiverDistance { .. })) {
+                Some(input.river.distance_to(move |r| r > river_level))
+            } else {
+                None
+            },
+        }
+    }
+}
+
+/// Material of the terrain, which gets its own channel in the splat maps
+#[derive(Clone, Debug)]
+pub struct Material {
+    /// all of the rules need to be satisfied for the material to be placed
+    pub rules: Vec<Rule>,
+    /// strength of the material compared to others where multiple materials are allowed
+    pub weight: f32,
+}
+
+impl Material {
+    pub fn new(rules: Vec<Rule>) -> Self {
+        Self { rules, weight: 1.0 }
+    }
+}
+
+/// The terrain and its derived maps the rules are evaluated on
+pub struct SplatInput<'a> {
+    pub terrain: &'a Map,
+    pub river: &'a Map,
+    pub lake: &'a Map,
+    pub bands: Bands,
+    /// value of the river map above which a cell counts as river
+    pub river_level: f32,
+}
+
+/// A sensible set of materials for an island: sand, grass, rock, snow, wet soil and shore
+pub fn default_materials() -> Vec<Material> {
+    vec![
+        // sand
+        Material::new(vec![Rule::Band {
+            from: Terrain::Water,
+            to: Terrain::Sand,
+            blend: 0.5,
+        }]),
+        // grass
+        Material::new(vec![
+            Rule::Band {
+                from: Terrain::Grass,
+                to: Terrain::Grass,
+                blend: 1.0,
+            },
+            Rule::Slope {
+                min: 0.0,
+                max: 35.0,
+                blend: 5.0,
+            },
+        ]),
+        // rock
+        Material::new(vec![Rule::Slope {
+            min: 35.0,
+            max: 90.0,
+            blend: 5.0,
+        }]),
+        // snow
+        Material::new(vec![
+            Rule::Band {
+                from: Terrain::Rock,
+                to: Terrain::Snow,
+                blend: 2.0,
+            },
+            Rule::Slope {
+                min: 0.0,
+                max: 45.0,
+                blend: 5.0,
+            },
+        ]),
+        // soil
+        Material::new(vec![Rule::Wetness {
+            min: 100.0,
+            max: f32::MAX,
+            blend: 50.0,
+        }]),
+        // shore
+        Material::new(vec![
+            Rule::LakeDistance {
+                min: 0.0,
+                max: 3.0,
+                blend: 3.0,
+            },
+            Rule::Band {
+                from: Terrain::Grass,
+                to: Terrain::Grass,
+                blend: 1.0,
+            },
+        ]),
+    ]
+}
+
+/// Factor of a value within `min..max`, falling off over `blend` outside of it
+fn ramp(value: f32, min: f32, max: f32, blend: f32) -> f32 {
+    let outside = (min - value).max(value - max);
+    if outside <= 0.0 {
+        1.0
+    } else if blend > 0.0 {
+        (1.0 - outside / blend).max(0.0)
+    } else {
+        0.0
+    }
+}
+
+/// Calculates the weight of each material on every cell.
+///
+/// The weights are normalized so that they sum up to `1` on each cell. Cells where no material
+/// applies are assigned to the first one.
+pub fn splat_weights(input: &SplatInput, materials: &[Material]) -> Vec<Map> {
+    let (width, height) = (input.terrain.width(), input.terrain.height());
+    let maps = RuleMaps::new(input, materials.iter().flat_map(|m| m.rules.iter()));
+
+    let mut weights: Vec<_> = materials
+        .iter()
+        .map(|material| {
+            let mut weight = Map::new(width, height);
+            weight.map_coords(|x, y, _| {
+                let factor = |rule: &Rule| rule.factor(input, &maps, (x, y));
+                material.weight * material.rules.iter().map(factor).product::<f32>()
+            });
+            weight
+        })
+        .collect();
+
+    let mut total = Map::new(width, height);
+    for weight in weights.iter() {
+        total += weight;
+    }
+
+    for (i, weight) in weights.iter_mut().enumerate() {
+        weight.map_coords(|x, y, w| {
+            let sum = total[(x, y)];
+            if sum > 0.0 {
+                w / sum
+            } else if i == 0 {
+                1.0
+            } else {
+                0.0
+            }
+        });
+    }
+
+    weights
+}
+
+/// Exports the material weights as RGBA png images, four materials per image.
+///
+/// `create` is called with the index of each image to get its output.
+#[allow(unused)]
+pub fn export_splat_maps<W: Write>(
+    weights: &[Map],
+    mut create: impl FnMut(usize) -> Result<W>,
+) -> Result<()> {
+    let (width, height) = match weights.first() {
+        Some(map) => (map.width(), map.height()),
+        None => return Ok(()),
+    };
+
+    for (layer, channels) in weights.chunks(4).enumerate() {
+        write_png_rows(
+            create(layer)?,
+            width,
+            height,
+            png::ColorType::RGBA,
+            |y, row| {
+                for (x, rgba) in row.chunks_mut(4).enumerate() {
+                    for (c, weight) in channels.iter().enumerate() {
+                        rgba[c] = (255.0 * weight[(x, y)]).round() as u8;
+                    }
+                }
+            },
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Check that the weights of all materials sum up to one on each cell
+#[test]
+fn test_weights_normalized() {
+    use crate::simplex::*;
+
+    let terrain = simplex_map(64, 64);
+    let river = Map::new(64, 64);
+    let mut lake = terrain.clone();
+    lake.map(|h| if h < 20.0 { 1.0 } else { 0.0 });
+
+    let (_, peak) = terrain.minmax();
+    let input = SplatInput {
+        terrain: &terrain,
+        river: &river,
+        lake: &lake,
+        bands: Bands::new(20.0, peak),
+        river_level: 100.0,
+    };
+    let weights = splat_weights(&input, &default_materials());
+
+    for x in 0..64 {
+        for y in 0..64 {
+            let sum: f32 = weights.iter().map(|w| w[(x, y)]).sum();
+            assert!((sum - 1.0).abs() < 1e-4);
+        }
+    }
+}
@@ -1,100 +1,206 @@
-use crate::{draw::*, map::*};
+use crate::{draw::*, geometry::*, map::*};
 use cgmath::{prelude::*, Vector2};
 use rand::prelude::*;
+use rayon::prelude::*;
+use std::collections::BTreeMap;
+use std::f32::consts::*;
+
+/// Upper limit of growth iterations, in case the tree never settles
+const MAX_ITERATIONS: usize = 10_000;
+
+/// Parameters of the space colonisation valleys
+#[derive(Clone, Debug)]
+pub struct ValleyConfig {
+    /// positions the valley trees grow from, random coastline points are used if empty
+    pub roots: Vec<(f32, f32)>,
+    /// number of random coastline roots, if no roots are given
+    pub root_count: usize,
+    /// number of attractors the valleys grow towards
+    pub attractors: usize,
+    /// attractors closer than this to a valley are removed
+    pub radius_death: f32,
+    /// attractors only pull valleys closer than this
+    pub radius_influence: f32,
+    /// length of a single valley segment
+    pub spawn_distance: f32,
+    /// how deep the largest valleys are carved into the terrain
+    pub depth: f32,
+    /// distance from the valley floor over which the carving fades out
+    pub width: f32,
+    pub seed: u64,
+}
+
+impl Default for ValleyConfig {
+    fn default() -> Self {
+        Self {
+            roots: Vec::new(),
+            root_count: 12,
+            attractors: 10_000,
+            radius_death: 10.0,
+            radius_influence: 20.0,
+            spawn_distance: 2.0,
+            depth: 12.0,
+            width: 12.0,
+            seed: 0,
+        }
+    }
+}
 
 struct Node {
     pos: Vector2<f32>,
     parent: usize,
 }
 
-fn attractors(width: usize, height: usize) -> Vec<Node> {
-    let mut rng = rand::thread_rng();
-    let count = 10_000;
-    let mut points = Vec::new();
+/// Point the valleys grow towards, together with the closest node within reach
+struct Attractor {
+    pos: Vector2<f32>,
+    closest: usize,
+    /// squared distance to the closest node
+    dist2: f32,
+}
 
-    let (width, height) = (width as f32, height as f32);
+/// Scatters attractors on the land of the map
+fn attractors(map: &Map, ocean: f32, count: usize, rng: &mut impl Rng) -> Vec<Attractor> {
+    let (width, height) = (map.width() as f32, map.height() as f32);
+    let mut points = Vec::new();
 
-    for _ in 0..count {
-        points.push(Node {
-            pos: Vector2::new(width * rng.gen::<f32>(), height * rng.gen::<f32>()),
-            parent: 0,
-        });
+    // give up eventually on maps with hardly any land
+    for _ in 0..count * 10 {
+        if points.len() >= count {
+            break;
+        }
+        let pos = Vector2::new(width * rng.gen::<f32>(), height * rng.gen::<f32>());
+        if map[(pos.x as usize, pos.y as usize)] >= ocean {
+            points.push(Attractor {
+                pos,
+                closest: usize::MAX,
+                dist2: f32::INFINITY,
+            });
+        }
     }
 
     points
 }
 
-pub fn generate_valleys(width: usize, height: usize) -> Map {
-    // radii squared, as to make it easier computation wise
-    let radius_death = 100.0;
-    let radius_influence = 400.0;
+/// Picks `count` random land cells next to the ocean
+fn coastline_roots(map: &Map, ocean: f32, count: usize, rng: &mut impl Rng) -> Vec<(f32, f32)> {
+    let mut coast = Vec::new();
+    for x in 1..map.width().saturating_sub(1) {
+        for y in 1..map.height().saturating_sub(1) {
+            let below = |x, y| map[(x, y)] < ocean;
+            if !below(x, y)
+                && (below(x - 1, y) || below(x + 1, y) || below(x, y - 1) || below(x, y + 1))
+            {
+                coast.push((x as f32, y as f32));
+            }
+        }
+    }
+    coast.choose_multiple(rng, count).cloned().collect()
+}
+
+/// Grows dendritic valleys from the coast into the land using space colonisation.
+///
+/// The result is a map of how deep each cell should be carved, between `0` and `1`.
+pub fn generate_valleys(map: &Map, ocean: f32, config: &ValleyConfig) -> Map {
+    let (width, height) = (map.width(), map.height());
+    let mut rng = StdRng::seed_from_u64(config.seed);
 
-    let spawn_distance = 2.0;
+    // radii squared, as to make it easier computation wise
+    let radius_death = config.radius_death * config.radius_death;
+    let radius_influence = config.radius_influence * config.radius_influence;
+    // nodes further away than this don't matter to an attractor
+    let reach = config.radius_influence.max(config.radius_death);
 
-    let mut attractors = attractors(width, height);
+    let roots = if config.roots.is_empty() {
+        coastline_roots(map, ocean, config.root_count, &mut rng)
+    } else {
+        config.roots.clone()
+    };
+    if roots.is_empty() {
+        return Map::new(width, height);
+    }
 
-    const NONE: usize = usize::MAX;
+    // roots are their own parents
+    let mut rivers: Vec<_> = roots
+        .iter()
+        .enumerate()
+        .map(|(i, &(x, y))| Node {
+            pos: Vector2::new(x, y),
+            parent: i,
+        })
+        .collect();
 
-    let mut rivers = vec![Node {
-        pos: Vector2::new(0.0, 0.0),
-        parent: 0,
-    }];
+    let mut attractors = attractors(map, ocean, config.attractors, &mut rng);
 
-    let mut influences = vec![Vector2::zero()];
+    // nodes that were added in the last iteration
+    let mut fresh = 0;
 
     // loop until a fixpoint is found
-    loop {
-        for inf in influences.iter_mut() {
-            *inf = Vector2::zero();
+    for _ in 0..MAX_ITERATIONS {
+        // only the new nodes can be closer to an attractor than its current closest node, so
+        // only they need to be looked up
+        let mut grid = SpatialGrid::new(width as f32, height as f32, reach);
+        for (idx, river) in rivers.iter().enumerate().skip(fresh) {
+            grid.insert(river.pos.x, river.pos.y, idx);
         }
-
-        for attr in attractors.iter() {
-            if attr.parent == NONE {
-                continue;
-            }
-            let apos = attr.pos;
-            let rpos = rivers[attr.parent].pos;
-            if apos.distance2(rpos) < radius_influence {
-                influences[attr.parent] += (apos - rpos).normalize();
+        attractors.par_iter_mut().for_each(|attr| {
+            for idx in grid.near(attr.pos.x, attr.pos.y, reach) {
+                let dist2 = attr.pos.distance2(rivers[idx].pos);
+                if dist2 < attr.dist2 {
+                    attr.closest = idx;
+                    attr.dist2 = dist2;
+                }
             }
+        });
+
+        attractors.retain(|attr| attr.dist2 >= radius_death);
+
+        let pulls: Vec<_> = attractors
+            .par_iter()
+            .filter(|attr| attr.dist2 < radius_influence)
+            .map(|attr| {
+                (
+                    attr.closest,
+                    (attr.pos - rivers[attr.closest].pos).normalize(),
+                )
+            })
+            .collect();
+
+        // only the few nodes with attractors in reach grow, kept in order to stay deterministic
+        let mut influences = BTreeMap::new();
+        for (idx, pull) in pulls.into_iter() {
+            *influences.entry(idx).or_insert_with(Vector2::zero) += pull;
         }
 
-        let mut new_rivers = Vec::new();
-        for ((river, influence), idx) in rivers.iter().zip(influences.iter_mut()).zip(0..) {
+        fresh = rivers.len();
+        for (idx, influence) in influences.into_iter() {
             if influence.magnitude2() > 0.01 {
-                new_rivers.push(Node {
-                    pos: river.pos + *influence * spawn_distance,
+                rivers.push(Node {
+                    pos: rivers[idx].pos + influence.normalize() * config.spawn_distance,
                     parent: idx,
                 });
             }
         }
 
-        if new_rivers.len() == 0 {
+        if fresh == rivers.len() {
             break;
         }
+    }
 
-        if new_rivers.len() > 0 {
-            for river in new_rivers.into_iter() {
-                for attr in attractors.iter_mut() {
-                    if attr.parent != NONE
-                        && attr.pos.distance2(rivers[attr.parent].pos)
-                            > attr.pos.distance2(river.pos)
-                    {
-                        attr.parent = rivers.len();
-                    }
-                }
-
-                rivers.push(river);
-                influences.push(Vector2::zero());
-            }
-        }
+    rasterize(&rivers, width, height, config.width)
+}
 
-        for attr in attractors.iter_mut() {
-            if attr.parent != NONE && attr.pos.distance2(rivers[attr.parent].pos) < radius_death {
-                attr.parent = NONE;
-            }
+/// Draws the valley tree, with valleys getting deeper the more branches flow into them
+fn rasterize(rivers: &[Node], width: usize, height: usize, valley_width: f32) -> Map {
+    // children are always added after their parents, so going backwards visits every node
+    // before its parent
+    let mut size = vec![1.0f32; rivers.len()];
+    for (i, river) in rivers.iter().enumerate().rev() {
+        if river.parent != i {
+            size[river.parent] += size[i];
         }
     }
+    let max_size = size.iter().cloned().fold(1.0, f32::max);
 
     let mut map = Map::new(width, height);
 
@@ -109,13 +215,115 @@ pub fn generate_valleys(width: usize, height: usize) -> Map {
         }
     };
 
-    for river in rivers.iter() {
+    for (river, size) in rivers.iter().zip(size.iter()) {
+        let strength = size.ln_1p() / max_size.ln_1p();
         let x1 = clamp(river.pos.x as _, width);
         let y1 = clamp(river.pos.y as _, height);
         let x2 = clamp(rivers[river.parent].pos.x as _, width);
         let y2 = clamp(rivers[river.parent].pos.y as _, height);
-        draw_line(&mut map, x1, y1, x2, y2, &|_| 1.0);
+        draw_line(&mut map, x1, y1, x2, y2, |h| h.max(strength));
     }
 
+    spread(&mut map, 1.0 / valley_width);
     map
 }
+
+/// Widens the valleys, by letting each value fall off linearly by `falloff` per cell.
+///
+/// It is a two pass chamfer transform like `Map::distance_to`, but keeping the maximum.
+fn spread(map: &mut Map, falloff: f32) {
+    let (width, height) = (map.width() as isize, map.height() as isize);
+
+    let relax = |map: &mut Map, x: isize, y: isize, dx: isize, dy: isize, len: f32| {
+        let (nx, ny) = (x + dx, y + dy);
+        if nx < 0 || ny < 0 || nx >= width || ny >= height {
+            return;
+        }
+        let v = map[(nx as usize, ny as usize)] - falloff * len;
+        let h = &mut map[(x as usize, y as usize)];
+        *h = h.max(v);
+    };
+
+    for y in 0..height {
+        for x in 0..width {
+            relax(map, x, y, -1, 0, 1.0);
+            relax(map, x, y, 0, -1, 1.0);
+            relax(map, x, y, -1, -1, SQRT_2);
+            relax(map, x, y, 1, -1, SQRT_2);
+        }
+    }
+    for y in (0..height).rev() {
+        for x in (0..width).rev() {
+            relax(map, x, y, 1, 0, 1.0);
+            relax(map, x, y, 0, 1, 1.0);
+            relax(map, x, y, 1, 1, SQRT_2);
+            relax(map, x, y, -1, 1, SQRT_2);
+        }
+    }
+}
+
+/// Carves the valleys into the terrain.
+///
+/// The valley profile is rounded, and land is never carved below the ocean.
+pub fn carve_valleys(map: &mut Map, valleys: &Map, ocean: f32, depth: f32) {
+    map.zip_with(valleys, |h, v| {
+        let carved = h - depth * v * v;
+        carved.max(ocean.min(h))
+    });
+}
+
+/// Check that valleys are deterministic for a seed and only ever lower the terrain, and that maps
+/// without a coast don't grow any
+#[test]
+fn test_carve_valleys() {
+    let size = 96;
+    let mut map = Map::new(size, size);
+    map.map_coords(|x, y, _| 60.0 - (x as f32 - 48.0).hypot(y as f32 - 48.0));
+
+    let config = ValleyConfig {
+        attractors: 500,
+        seed: 7,
+        ..ValleyConfig::default()
+    };
+    let valleys = generate_valleys(&map, 20.0, &config);
+    let again = generate_valleys(&map, 20.0, &config);
+
+    let (_, max) = valleys.minmax();
+    assert!(max > 0.0);
+
+    let mut carved = map.clone();
+    carve_valleys(&mut carved, &valleys, 20.0, config.depth);
+    for x in 0..size {
+        for y in 0..size {
+            assert_eq!(valleys[(x, y)], again[(x, y)]);
+            assert!(carved[(x, y)] <= map[(x, y)]);
+            assert!(carved[(x, y)] >= map[(x, y)].min(20.0));
+        }
+    }
+
+    for &(width, height) in [(0, 0), (0, 5), (5, 0), (1, 1)].iter() {
+        let valleys = generate_valleys(&Map::new(width, height), 20.0, &config);
+        assert_eq!((valleys.width(), valleys.height()), (width, height));
+    }
+}
+
+/// Measure how long the valleys of a 2000x2000 island with 100k attractors take to grow, run it
+/// with `cargo test --release -- --ignored --nocapture bench`
+#[test]
+#[ignore]
+fn bench_generate_valleys() {
+    use crate::simplex::*;
+    use std::time::Instant;
+
+    let map = simplex_map(2000, 2000);
+    let config = ValleyConfig {
+        attractors: 100_000,
+        ..ValleyConfig::default()
+    };
+
+    let start = Instant::now();
+    let valleys = generate_valleys(&map, 20.0, &config);
+    println!("{: <25}({:.3} s)", "Valleys", start.elapsed().as_secs_f32());
+    let (_, max) = valleys.minmax();
+    assert!(max > 0.0);
+}
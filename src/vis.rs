@@ -1,7 +1,9 @@
-use crate::map::*;
+use crate::{contour::*, geometry::*, light::*, map::*};
 use png::*;
+use rayon::prelude::*;
 use std::io::prelude::*;
-use std::io::{Error, ErrorKind, Result};
+use std::io::Result;
+use std::ops::Range;
 
 #[derive(Copy, Clone)]
 struct Color([f32; 3]);
@@ -9,14 +11,14 @@ struct Color([f32; 3]);
 impl Color {
     fn mix(&self, other: &Self, f: f32) -> Self {
         let mut new = [0.0; 3];
-        for i in 0..3 {
-            new[i] = self.0[i] * (1.0 - f) + other.0[i] * f;
+        for (n, (a, b)) in new.iter_mut().zip(self.0.iter().zip(other.0.iter())) {
+            *n = a * (1.0 - f) + b * f;
         }
         Self(new)
     }
     fn write_to_rgb_buf(self, buf: &mut [u8]) {
-        for i in 0..3 {
-            buf[i] = (self.0[i] * 255.0) as u8;
+        for (b, c) in buf.iter_mut().zip(self.0.iter()) {
+            *b = (c * 255.0) as u8;
         }
     }
     fn from(rgb: [u8; 3]) -> Self {
@@ -28,108 +30,323 @@ impl Color {
     }
 }
 
-pub fn visualize(map: &Map, river: &Map, lake: &Map, ocean: f32, out: impl Write) -> Result<()> {
+/// Kinds of terrain, distinguished by their height
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Terrain {
+    Water,
+    WetSand,
+    Sand,
+    Grass,
+    Rock,
+    Snow,
+}
+
+impl Terrain {
+    pub const ALL: [Terrain; 6] = [
+        Terrain::Water,
+        Terrain::WetSand,
+        Terrain::Sand,
+        Terrain::Grass,
+        Terrain::Rock,
+        Terrain::Snow,
+    ];
+}
+
+/// Height bands of the different kinds of terrain.
+///
+/// They are used by the renderer to color the terrain and by the splat maps to place materials,
+/// so that both look alike.
+#[derive(Copy, Clone, Debug)]
+pub struct Bands {
+    /// upper height limit of every kind of terrain except snow, in the order of `Terrain::ALL`
+    limits: [f32; 5],
+}
+
+impl Bands {
+    /// Bands of an island with the given ocean height and highest peak
+    pub fn new(ocean: f32, peak: f32) -> Self {
+        // rock and snow start at least 10 above the ocean, so that low islands keep their
+        // beaches and grass
+        let snow = (peak - 19.0).max(ocean + 10.0);
+        Self {
+            limits: [ocean + 0.001, ocean + 1.0, ocean + 2.2, snow, snow + 7.0],
+        }
+    }
+
+    /// Lowest and highest height of a kind of terrain
+    pub fn range(&self, terrain: Terrain) -> (f32, f32) {
+        let idx = terrain as usize;
+        let min = if idx == 0 {
+            f32::MIN
+        } else {
+            self.limits[idx - 1]
+        };
+        let max = self.limits.get(idx).cloned().unwrap_or(f32::MAX);
+        (min, max)
+    }
+
+    /// Kind of terrain at a certain height
+    pub fn terrain(&self, height: f32) -> Terrain {
+        let idx = self.limits.iter().take_while(|&&l| height >= l).count();
+        Terrain::ALL[idx]
+    }
+}
+
+/// Rendering options of [`visualize`](./fn.visualize.html)
+#[derive(Copy, Clone, Debug)]
+pub struct Style {
+    /// position of the sun lighting the terrain
+    pub sun: Sun,
+    /// light the terrain from multiple directions around the sun to keep more details visible
+    pub multi_directional: bool,
+    /// whether the terrain casts (soft) shadows
+    pub shadows: bool,
+    /// size of the steps the light is quantised into, which must be positive, `None` for smooth
+    /// shading
+    pub banding: Option<f32>,
+    /// contour lines drawn on top of the terrain, `None` to disable them
+    pub contours: Option<Contours>,
+}
+
+impl Default for Style {
+    fn default() -> Self {
+        Self {
+            sun: Sun::default(),
+            multi_directional: false,
+            shadows: true,
+            banding: Some(0.125),
+            contours: Some(Contours::default()),
+        }
+    }
+}
+
+/// Things drawn on top of the rendered terrain, for example to debug object placement
+#[derive(Clone, Debug, Default)]
+pub struct Overlay {
+    pub lines: Vec<Polyline>,
+    pub markers: Vec<Marker>,
+}
+
+/// Line through multiple points drawn on top of the terrain
+#[derive(Clone, Debug)]
+pub struct Polyline {
+    pub points: Vec<(f32, f32)>,
+    /// width in pixels
+    pub width: f32,
+    pub color: [u8; 3],
+}
+
+/// Filled circle drawn on top of the terrain
+#[derive(Copy, Clone, Debug)]
+pub struct Marker {
+    pub x: f32,
+    pub y: f32,
+    /// radius in pixels
+    pub radius: f32,
+    pub color: [u8; 3],
+}
+
+pub fn visualize(
+    map: &Map,
+    lake: &Map,
+    ocean: f32,
+    style: &Style,
+    overlay: &Overlay,
+    out: impl Write,
+) -> Result<()> {
+    if let Some(step) = style.banding {
+        assert!(step > 0.0, "banding step {} isn't positive", step);
+    }
+
     let mut map = map.clone();
     map.map(|h| if h < ocean { ocean } else { h });
     let map = &map;
 
     let (_, peak) = map.minmax();
-
-    let mut buffer = vec![0u8; 3 * map.width() * map.height()];
+    let bands = Bands::new(ocean, peak);
 
     let col_ocean = Color::from([77, 77, 140]);
     let col_ambient = Color([0.0; 3]);
-    let col_lines = Color([1.0, 1.0, 1.0]);
     let col_sand0 = Color::from([164, 149, 122]);
     let col_sand1 = Color::from([212, 214, 174]);
     let col_default = Color::from([158, 182, 119]);
     let col_snow0 = Color::from([160, 161, 155]);
     let col_snow1 = Color::from([255; 3]);
 
-    // inclination angle of the sun
-    // the light always comes from -x -y direction
-    let angle = 0.2;
-
-    let shade = shade_map(map, angle);
-
-    for x in 0..map.width() {
-        for y in 0..map.height() {
-            let light = if map[(x, y)] < shade[(x, y)] {
-                0.0
-            } else {
-                let diff = if x > 0 && y > 0 {
-                    map[(x, y)] - map[(x - 1, y - 1)]
-                } else {
-                    0.0
-                };
-
-                let p = (2.0f32).sqrt();
-                let l = (angle / p).atan();
-                let r = (diff / p).atan();
-
-                let target = std::f32::consts::PI - l - r;
-                target.sin().powf(0.5)
-            };
-
-            // banding
-            let light = light - (light % 0.125);
-
-            let shade = 0.5 * (1.0 - light);
-            let height = map[(x, y)];
-            let snow_height = peak - 19.0;
-            let col = if height < ocean + 0.001 || lake[(x, y)] > 0.0 {
-                col_ocean
-            } else if height < ocean + 1.0 {
-                col_sand0
-            } else if height < ocean + 2.2 {
-                col_sand1
-            } else if height < snow_height {
-                col_default
-            } else if height < snow_height + 7.0 {
-                col_snow0
-            } else {
-                col_snow1
-            };
-
-            let col = col.mix(&col_ambient, shade);
-            let col = col.mix(&col_lines, height_line(map[(x, y)]));
-            col.write_to_rgb_buf(&mut buffer[3 * map.flatten_xy(x, y)..]);
-        }
+    let mut light = if style.multi_directional {
+        multi_directional_hillshade(map, &style.sun)
+    } else {
+        hillshade(map, &style.sun)
+    };
+    // soften the contrast between steep and flat slopes
+    light.map(f32::sqrt);
+
+    if style.shadows {
+        let shadow = shadow_map(map, &style.sun);
+        light *= &shadow;
     }
 
-    let mut encoder = Encoder::new(out, map.width() as _, map.height() as _);
-    encoder.set_color(ColorType::RGB);
-    encoder.set_depth(BitDepth::Eight);
-    let mut writer = encoder.write_header()?;
-    writer
-        .write_image_data(&buffer)
-        .map_err(|_| Error::new(ErrorKind::Other, "PNG encoding error"))
+    let lines = style.contours.map(|contours| {
+        let color = Color::from(contours.color);
+        (color, contours.rasterize(map))
+    });
+
+    let width = map.width();
+    write_png(out, width, map.height(), ColorType::RGB, |rows, band| {
+        band.par_chunks_mut(3 * width)
+            .zip(rows.clone())
+            .for_each(|(row, y)| {
+                for (x, rgb) in row.chunks_mut(3).enumerate() {
+                    let mut light = light[(x, y)];
+                    if let Some(step) = style.banding {
+                        light -= light % step;
+                    }
+
+                    let shade = 0.5 * (1.0 - light);
+                    let terrain = if lake[(x, y)] > 0.0 {
+                        Terrain::Water
+                    } else {
+                        bands.terrain(map[(x, y)])
+                    };
+                    let col = match terrain {
+                        Terrain::Water => col_ocean,
+                        Terrain::WetSand => col_sand0,
+                        Terrain::Sand => col_sand1,
+                        Terrain::Grass => col_default,
+                        Terrain::Rock => col_snow0,
+                        Terrain::Snow => col_snow1,
+                    };
+
+                    let col = col.mix(&col_ambient, shade);
+                    let col = match &lines {
+                        Some((col_lines, alpha)) => col.mix(col_lines, alpha[(x, y)]),
+                        None => col,
+                    };
+                    col.write_to_rgb_buf(rgb);
+                }
+            });
+
+        for line in overlay.lines.iter() {
+            draw_polyline(band, width, rows.clone(), line);
+        }
+        for marker in overlay.markers.iter() {
+            draw_marker(band, width, rows.clone(), marker);
+        }
+    })
 }
 
-fn height_line(h: f32) -> f32 {
-    let h = (h + 5.0) % 10.0;
-    if h < 0.8 {
-        0.2
-    } else {
-        0.0
+/// Draws the part of an anti-aliased polyline within `rows` into an RGB buffer of these rows
+fn draw_polyline(buffer: &mut [u8], width: usize, rows: Range<usize>, line: &Polyline) {
+    if line.points.is_empty() {
+        return;
+    }
+
+    // bounding box of the line, in which the coverage of each pixel is collected first so that
+    // the joints between segments aren't drawn twice
+    let reach = 0.5 * line.width + 1.0;
+    let min_x = line.points.iter().map(|p| p.0).fold(f32::MAX, f32::min);
+    let min_y = line.points.iter().map(|p| p.1).fold(f32::MAX, f32::min);
+    let max_x = line.points.iter().map(|p| p.0).fold(f32::MIN, f32::max);
+    let max_y = line.points.iter().map(|p| p.1).fold(f32::MIN, f32::max);
+    let x0 = (min_x - reach).max(0.0) as usize;
+    let y0 = (min_y - reach).max(rows.start as f32) as usize;
+    let x1 = ((max_x + reach) as usize).min(width - 1);
+    let y1 = ((max_y + reach) as usize).min(rows.end - 1);
+    if x0 > x1 || y0 > y1 {
+        return;
     }
-}
 
-fn shade_map(map: &Map, angle: f32) -> Map {
-    let mut shade = Map::new(map.width(), map.height());
+    let mut coverage = Map::new(x1 - x0 + 1, y1 - y0 + 1);
+    for (a, b) in line.points.windows(2).map(|w| (w[0], w[1])) {
+        let sx0 = (a.0.min(b.0) - reach).max(x0 as f32) as usize;
+        let sy0 = (a.1.min(b.1) - reach).max(y0 as f32) as usize;
+        let sx1 = ((a.0.max(b.0) + reach) as usize).min(x1);
+        let sy1 = ((a.1.max(b.1) + reach) as usize).min(y1);
 
-    for x in 0..map.width() {
-        shade[(x, 0)] = map[(x, 0)];
+        for y in sy0..=sy1 {
+            for x in sx0..=sx1 {
+                let dist = distance_to_segment((x as f32, y as f32), a, b);
+                let c = (0.5 * line.width + 0.5 - dist).clamp(0.0, 1.0);
+                let cov = &mut coverage[(x - x0, y - y0)];
+                *cov = cov.max(c);
+            }
+        }
     }
-    for y in 0..map.height() {
-        shade[(0, y)] = map[(0, y)];
+
+    let color = Color::from(line.color);
+    for y in y0..=y1 {
+        for x in x0..=x1 {
+            let c = coverage[(x - x0, y - y0)];
+            if c > 0.0 {
+                blend_pixel(buffer, 3 * (x + (y - rows.start) * width), &color, c);
+            }
+        }
     }
+}
+
+/// Mixes the color of a pixel in an RGB buffer with `color`
+fn blend_pixel(buffer: &mut [u8], idx: usize, color: &Color, f: f32) {
+    let background = Color::from([buffer[idx], buffer[idx + 1], buffer[idx + 2]]);
+    background
+        .mix(color, f)
+        .write_to_rgb_buf(&mut buffer[idx..]);
+}
+
+/// Draws the part of an anti-aliased marker within `rows` into an RGB buffer of these rows
+fn draw_marker(buffer: &mut [u8], width: usize, rows: Range<usize>, marker: &Marker) {
+    let color = Color::from(marker.color);
+    let reach = marker.radius + 1.0;
 
-    for y in 1..map.height() {
-        for x in 1..map.height() {
-            shade[(x, y)] = map[(x, y)].max(shade[(x - 1, y - 1)] - angle);
+    let x0 = (marker.x - reach).max(0.0) as usize;
+    let y0 = (marker.y - reach).max(rows.start as f32) as usize;
+    let x1 = ((marker.x + reach) as usize).min(width - 1);
+    let y1 = ((marker.y + reach) as usize).min(rows.end - 1);
+
+    for y in y0..=y1 {
+        for x in x0..=x1 {
+            let dist = (x as f32 - marker.x).hypot(y as f32 - marker.y);
+            let coverage = (marker.radius + 0.5 - dist).clamp(0.0, 1.0);
+            if coverage > 0.0 {
+                blend_pixel(buffer, 3 * (x + (y - rows.start) * width), &color, coverage);
+            }
         }
     }
+}
+
+/// Check that maps which aren't square are rendered and exported with the right dimensions
+#[test]
+fn test_rectangular_export() {
+    use crate::analysis::*;
+
+    let (width, height) = (90, 40);
+    let mut map = Map::new(width, height);
+    map.map_coords(|x, y, _| 20.0 + (x as f32 * 0.3).sin() * 10.0 + y as f32 * 0.2);
+    let lake = Map::new(width, height);
+
+    let dimensions = |buffer: Vec<u8>| {
+        let (info, _) = png::Decoder::new(&buffer[..]).read_info().unwrap();
+        (info.width as usize, info.height as usize)
+    };
+
+    let mut rendered = Vec::new();
+    let style = Style::default();
+    visualize(
+        &map,
+        &lake,
+        21.0,
+        &style,
+        &Overlay::default(),
+        &mut rendered,
+    )
+    .unwrap();
+    assert_eq!(dimensions(rendered), (width, height));
+
+    let mut heightmap = Vec::new();
+    map.export_image(&mut heightmap).unwrap();
+    assert_eq!(dimensions(heightmap), (width, height));
 
-    shade
+    let mut normals = Vec::new();
+    map.export_normal_map(&mut normals, 1.0, NormalConvention::OpenGl)
+        .unwrap();
+    assert_eq!(dimensions(normals), (width, height));
 }
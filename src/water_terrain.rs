@@ -1,8 +1,229 @@
-use crate::map::*;
-use std::collections::*;
+use crate::{map::*, resample::*, slope::*};
+use rayon::prelude::*;
+#[cfg(test)]
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{BufReader, Result};
+use std::path::Path;
+use std::sync::Arc;
+
+/// Parameters of the terrain generation
+#[derive(Clone)]
+pub struct TerrainConfig {
+    /// slope of the terrain depending on the rivers, which shapes the valleys
+    pub slope_model: Arc<dyn SlopeModel>,
+    /// factor for the slope of every cell, to make areas flatter (below `1`) or steeper (above
+    /// `1`), negative factors count as `0`
+    pub slope: Option<Map>,
+    /// treat the map as tileable, so that the terrain continues across the edges
+    pub wrap: bool,
+}
+
+impl Default for TerrainConfig {
+    fn default() -> Self {
+        Self {
+            slope_model: Arc::new(InverseSlope::default()),
+            slope: None,
+            wrap: false,
+        }
+    }
+}
+
+/// Loads a slope map for [`TerrainConfig::slope`](./struct.TerrainConfig.html#structfield.slope)
+/// and resizes it to `width` by `height`.
+///
+/// Files saved with [`Map::save`](../map/type.Map.html#method.save) hold the factors themselves.
+/// Any other file is read as a png image painted in grey, where black is flat, mid grey keeps the
+/// slope as it is and white makes the terrain twice as steep.
+pub fn load_slope_map(path: impl AsRef<Path>, width: usize, height: usize) -> Result<Map> {
+    let path = path.as_ref();
+    let slope = if path.extension() == Some("grid".as_ref()) {
+        Map::load(path)?
+    } else {
+        Map::import_image_range(BufReader::new(File::open(path)?), 0.0, 2.0)?
+    };
+    if (slope.width(), slope.height()) == (width, height) {
+        Ok(slope)
+    } else {
+        Ok(slope.resize(width, height, Filter::Bilinear))
+    }
+}
 
 /// creates a height map based on the river and lake `Map`'s.
-pub fn create_heightmap(rivers: &Map, lakes: &Map) -> Map {
+///
+/// The terrain rises from the lakes, with the height of each cell being the lowest sum of the
+/// slopes along a path to a lake. Diagonal steps are longer than the others by a factor of `√2`,
+/// so that hills and valleys come out round instead of as octagons.
+pub fn create_heightmap(rivers: &Map, lakes: &Map, config: &TerrainConfig) -> Map {
+    let (width, height) = (rivers.width(), rivers.height());
+    let wrap = config.wrap;
+    if let Some(slope) = &config.slope {
+        assert_eq!((slope.width(), slope.height()), (width, height));
+    }
+    let mut terrain = lakes.clone();
+    terrain.map(|z| z.max(0.0));
+
+    // lake cells without any neighbors above their water level can't raise any of them, so only
+    // the shores are queued, which keeps the queue small on large maps
+    let shores: Vec<Cell> = (0..height)
+        .into_par_iter()
+        .flat_map_iter(|y| {
+            (0..width).filter_map(move |x| {
+                let z = lakes[(x, y)];
+                if z <= 0.0 {
+                    return None;
+                }
+                let inland = lakes
+                    .neighbors8(x, y, wrap)
+                    .all(|n| lakes[n] > 0.0 && lakes[n] <= z);
+                if inland {
+                    None
+                } else {
+                    Some(Cell::new(lakes, x, y, z))
+                }
+            })
+        })
+        .collect();
+
+    // points sorted by lowest z coordinate first
+    //
+    // adding lakes points first, as the terrain is generated lowest
+    // first beginning from the lakes
+    let mut queue = CellQueue::new();
+    for cell in shores.into_iter() {
+        queue.push(cell);
+    }
+
+    let mut done = Grid::<bool>::new(width, height);
+
+    while let Some(cell) = queue.pop() {
+        let z = cell.z;
+        let (x, y) = terrain.unflatten(cell.idx as usize);
+
+        if done[(x, y)] {
+            // terrain has already been generated here earlier, no need to do it again
+            continue;
+        }
+
+        done[(x, y)] = true;
+
+        if !wrap && (x == 0 || x == width - 1 || y == 0 || y == height - 1) {
+            // neighbors are not searched for border positions, as this should all be
+            // ocean anyways
+            continue;
+        }
+
+        // steeper if there is not much water flowing through
+        let mut dz = config.slope_model.slope(rivers[(x, y)]).max(0.0);
+        if let Some(slope) = &config.slope {
+            dz *= slope[(x, y)].max(0.0);
+        }
+
+        let interior = x > 0 && x < width - 1 && y > 0 && y < height - 1;
+        for dx in -1..=1 {
+            for dy in -1..=1 {
+                // finding the neighbor, which is always inside the map as borders are skipped,
+                // away from the edges it is found without checking for wrapping
+                let (nx, ny) = if interior {
+                    ((x as isize + dx) as usize, (y as isize + dy) as usize)
+                } else {
+                    terrain.offset(x, y, dx, dy, wrap).unwrap()
+                };
+
+                if done[(nx, ny)] {
+                    // if already generated, don't bother
+                    continue;
+                }
+
+                let nz = if dx != 0 && dy != 0 {
+                    z + dz * std::f32::consts::SQRT_2
+                } else {
+                    z + dz
+                };
+                if terrain[(nx, ny)] != 0.0 && terrain[(nx, ny)] < nz {
+                    continue;
+                }
+                // add neighboring point to queue to process further in the correct order
+                queue.push(Cell::new(&terrain, nx, ny, nz));
+                terrain[(nx, ny)] = nz;
+            }
+        }
+    }
+
+    terrain
+}
+
+/// Cell in the queue of the terrain generation, which is a lot smaller than a `Point` as there
+/// can be many of them on large maps
+#[derive(PartialEq, Debug)]
+struct Cell {
+    z: f32,
+    idx: u32,
+}
+
+impl Cell {
+    fn new(map: &Map, x: usize, y: usize, z: f32) -> Self {
+        Self {
+            z,
+            idx: map.flatten_xy(x, y) as u32,
+        }
+    }
+}
+
+/// Priority queue of cells, which pops the lowest cell first.
+///
+/// It is a radix heap, which relies on no cell being pushed lower than the last one popped, as it
+/// is the case when the terrain is built up from the lowest cells. The cells are put into buckets
+/// by the highest bit in which their height differs from the last one popped. Pushing is constant
+/// time and every cell moves down the buckets at most 32 times, which is a lot faster than a
+/// binary heap with the many cells of large maps.
+///
+/// Heights are compared by their bits, which are ordered like the heights as they are positive.
+struct CellQueue {
+    /// bits of the height of the last cell popped
+    last: u32,
+    buckets: Vec<Vec<Cell>>,
+}
+
+impl CellQueue {
+    fn new() -> Self {
+        Self {
+            last: 0,
+            buckets: (0..=32).map(|_| Vec::new()).collect(),
+        }
+    }
+
+    fn bucket(&self, z: f32) -> usize {
+        (32 - (z.to_bits() ^ self.last).leading_zeros()) as usize
+    }
+
+    fn push(&mut self, cell: Cell) {
+        debug_assert!(cell.z > 0.0 && cell.z.to_bits() >= self.last);
+        let bucket = self.bucket(cell.z);
+        self.buckets[bucket].push(cell);
+    }
+
+    fn pop(&mut self) -> Option<Cell> {
+        if self.buckets[0].is_empty() {
+            let idx = self.buckets.iter().position(|b| !b.is_empty())?;
+            let mut bucket = std::mem::take(&mut self.buckets[idx]);
+            self.last = bucket.iter().map(|c| c.z.to_bits()).min().unwrap();
+
+            // the cells only differ from the new lowest one in lower bits, so they all move to
+            // lower buckets, and the bucket is put back empty to reuse its memory
+            for cell in bucket.drain(..) {
+                let b = self.bucket(cell.z);
+                self.buckets[b].push(cell);
+            }
+            self.buckets[idx] = bucket;
+        }
+        self.buckets[0].pop()
+    }
+}
+
+/// Creates the height map with a binary heap instead of the radix heap
+#[cfg(test)]
+fn binary_heap_heightmap(rivers: &Map, lakes: &Map, wrap: bool) -> Map {
     let (width, height) = (rivers.width(), rivers.height());
     let mut terrain = Map::new(width, height);
 
@@ -15,25 +236,41 @@ pub fn create_heightmap(rivers: &Map, lakes: &Map) -> Map {
         for y in 0..height {
             let z = lakes[(x, y)];
             if z > 0.0 {
-                queue.push(Point { x, y, z });
                 terrain[(x, y)] = z;
+
+                // lake cells without any neighbors above their water level can't raise any of
+                // them, so only the shores are queued, which keeps the queue small on large maps
+                let inland = lakes
+                    .neighbors8(x, y, wrap)
+                    .all(|n| lakes[n] > 0.0 && lakes[n] <= z);
+                if !inland {
+                    queue.push(std::cmp::Reverse((
+                        z.to_bits(),
+                        terrain.flatten_xy(x, y) as u32,
+                    )));
+                }
             }
         }
     }
 
-    let mut done = vec![vec![false; height]; width];
+    let mut done = Grid::<bool>::new(width, height);
 
-    while let Some(point) = queue.pop() {
-        let Point { x, y, z } = point;
+    while let Some(std::cmp::Reverse((z, idx))) = queue.pop() {
+        let cell = Cell {
+            z: f32::from_bits(z),
+            idx,
+        };
+        let z = cell.z;
+        let (x, y) = terrain.unflatten(cell.idx as usize);
 
-        if done[x][y] {
+        if done[(x, y)] {
             // terrain has already been generated here earlier, no need to do it again
             continue;
         }
 
-        done[x][y] = true;
+        done[(x, y)] = true;
 
-        if x == 0 || x == width - 1 || y == 0 || y == height - 1 {
+        if !wrap && (x == 0 || x == width - 1 || y == 0 || y == height - 1) {
             // neighbors are not searched for border positions, as this should all be
             // ocean anyways
             continue;
@@ -41,28 +278,30 @@ pub fn create_heightmap(rivers: &Map, lakes: &Map) -> Map {
 
         for dx in -1..=1 {
             for dy in -1..=1 {
-                // finding neighbor x,y
-                let nx = ((x as isize) + dx) as usize;
-                let ny = ((y as isize) + dy) as usize;
+                // finding neighbor x,y, which is always inside the map as borders are skipped
+                let (nx, ny) = terrain.offset(x, y, dx, dy, wrap).unwrap();
 
-                if done[nx][ny] {
+                if done[(nx, ny)] {
                     // if already generated, don't bother
                     continue;
                 }
 
                 // steeper if there is not much water flowing through
                 let dz = 1.0 / (0.5 + rivers[(x, y)]);
-                let nz = z + dz;
+                let nz = if dx != 0 && dy != 0 {
+                    z + dz * std::f32::consts::SQRT_2
+                } else {
+                    z + dz
+                };
 
                 if terrain[(nx, ny)] != 0.0 && terrain[(nx, ny)] < nz {
                     continue;
                 }
                 // add neighboring point to queue to process further in the correct order
-                queue.push(Point {
-                    x: nx,
-                    y: ny,
-                    z: nz,
-                });
+                queue.push(std::cmp::Reverse((
+                    nz.to_bits(),
+                    terrain.flatten_xy(nx, ny) as u32,
+                )));
                 terrain[(nx, ny)] = nz;
             }
         }
@@ -82,3 +321,231 @@ fn test_point_sorting_order() {
 
     assert!(first.z < second.z);
 }
+
+/// Check that the queue pops the cells from lowest to highest, with cells being pushed in between
+#[test]
+fn test_cell_queue_order() {
+    use rand::prelude::*;
+
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut queue = CellQueue::new();
+    queue.push(Cell { z: 2.0, idx: 0 });
+    queue.push(Cell { z: 1.0, idx: 0 });
+
+    let mut last = 0.0;
+    let mut popped = 0;
+    while let Some(cell) = queue.pop() {
+        assert!(cell.z >= last);
+        last = cell.z;
+        popped += 1;
+        if popped < 10_000 {
+            for _ in 0..rng.gen_range(1..3) {
+                let z = last + rng.gen_range(0.0..1.0) * rng.gen_range(0.0..1.0f32).powi(8);
+                queue.push(Cell { z, idx: 0 });
+            }
+        }
+    }
+    assert!(popped >= 10_000);
+}
+
+/// Check that the stages work on maps that aren't square, down to maps barely wider than the
+/// range of the flow targets
+#[test]
+fn test_rectangular_terrain() {
+    use crate::{flow::*, lake::*, river::*, simplex::*};
+
+    for &(width, height) in [(150, 60), (60, 150), (8, 120), (120, 8)].iter() {
+        let map = simplex_map(width, height);
+        let targets = find_targets(&map, 6, false);
+        assert_eq!((targets.width(), targets.height()), (width, height));
+
+        let mut rivers = create_flow_map(&map, &targets);
+        let lakes = lake_map(&map, &rivers, &targets, 20.0);
+        rivers.map(|h| h.powf(0.45));
+        let terrain = create_heightmap(&rivers, &lakes, &TerrainConfig::default());
+        assert_eq!((terrain.width(), terrain.height()), (width, height));
+
+        assert!((0..width).all(|x| (0..height).all(|y| terrain[(x, y)].is_finite())));
+
+        // the edges are ocean, except on maps too thin for the edges to be scaled down
+        if width.min(height) >= 20 {
+            for x in 0..width {
+                assert!(lakes[(x, 0)] > 0.0 && lakes[(x, height - 1)] > 0.0);
+            }
+            for y in 0..height {
+                assert!(lakes[(0, y)] > 0.0 && lakes[(width - 1, y)] > 0.0);
+            }
+        }
+    }
+}
+
+/// Check that the terrain of a tileable map matches up at opposite edges
+#[test]
+fn test_tileable_terrain() {
+    use crate::{flow::*, lake::*, river::*, simplex::*};
+
+    let (width, height) = (128, 96);
+    let map = tileable_map(width, height, &PRESETS[0], 0);
+    let targets = find_targets(&map, 6, true);
+    let mut rivers = create_flow_map(&map, &targets);
+    let lakes = lake_map(&map, &rivers, &targets, 20.0);
+    rivers.map(|h| h.powf(0.45));
+    let config = TerrainConfig {
+        wrap: true,
+        ..TerrainConfig::default()
+    };
+    let terrain = create_heightmap(&rivers, &lakes, &config);
+
+    // neighbors differ by at most the steepest step of the terrain, as every cell of the river map
+    // is at least 1
+    let step = 1.0 / (0.5 + 1.0);
+    for y in 0..height {
+        assert!((terrain[(0, y)] - terrain[(width - 1, y)]).abs() <= step + 1e-4);
+    }
+    for x in 0..width {
+        assert!((terrain[(x, 0)] - terrain[(x, height - 1)]).abs() <= step + 1e-4);
+    }
+}
+
+/// Check that the terrain built with the radix heap matches the one built with a binary heap, on
+/// square, rectangular and tileable maps
+#[test]
+fn test_radix_heap_terrain() {
+    use crate::{flow::*, lake::*, river::*, simplex::*};
+
+    for &(width, height, wrap) in [(200, 200, false), (250, 120, false), (160, 200, true)].iter() {
+        let map = if wrap {
+            tileable_map(width, height, &PRESETS[0], 0)
+        } else {
+            simplex_map(width, height)
+        };
+        let targets = find_targets(&map, 6, wrap);
+        let mut rivers = create_flow_map(&map, &targets);
+        let lakes = lake_map(&map, &rivers, &targets, 20.0);
+        rivers.map(|h| h.powf(0.45));
+
+        let config = TerrainConfig {
+            wrap,
+            ..TerrainConfig::default()
+        };
+        let terrain = create_heightmap(&rivers, &lakes, &config);
+        let expected = binary_heap_heightmap(&rivers, &lakes, wrap);
+        for x in 0..width {
+            for y in 0..height {
+                assert_eq!(terrain[(x, y)], expected[(x, y)]);
+            }
+        }
+    }
+}
+
+/// Compares the time it takes to build the terrain of a 2000 by 2000 island with the radix heap
+/// and with a binary heap, run it with `cargo test --release -- --ignored --nocapture bench`
+#[test]
+#[ignore]
+fn bench_create_heightmap() {
+    use crate::{flow::*, lake::*, river::*, simplex::*};
+    use std::time::Instant;
+
+    let map = simplex_map(2000, 2000);
+    let targets = find_targets(&map, 6, false);
+    let mut rivers = create_flow_map(&map, &targets);
+    let lakes = lake_map(&map, &rivers, &targets, 20.0);
+    rivers.map(|h| h.powf(0.45));
+
+    let time = |name: &str, fun: &dyn Fn() -> Map| {
+        let runs = 3;
+        let start = Instant::now();
+        for _ in 0..runs {
+            fun();
+        }
+        let elapsed = start.elapsed().as_secs_f32() / runs as f32;
+        println!("{: <25}({:.3} s)", name, elapsed);
+    };
+    time("Radix Heap", &|| {
+        create_heightmap(&rivers, &lakes, &TerrainConfig::default())
+    });
+    time("Binary Heap", &|| {
+        binary_heap_heightmap(&rivers, &lakes, false)
+    });
+}
+
+/// Check that the terrain rises about equally fast in every direction, and that the slope map
+/// makes it flatter where it is lower
+#[test]
+fn test_round_terrain() {
+    let size = 101;
+    let center = 50;
+    let rivers = Map::new(size, size);
+    let mut lakes = Map::new(size, size);
+    lakes[(center, center)] = 20.0;
+
+    // height above the lake of the cells at a certain distance from it
+    let ring = |terrain: &Map, radius: f32| {
+        let mut heights = Vec::new();
+        for x in 0..size {
+            for y in 0..size {
+                let dist = (x as f32 - center as f32).hypot(y as f32 - center as f32);
+                if (dist - radius).abs() < 0.5 {
+                    heights.push(terrain[(x, y)] - 20.0);
+                }
+            }
+        }
+        let min = heights.iter().cloned().fold(f32::MAX, f32::min);
+        let max = heights.iter().cloned().fold(f32::MIN, f32::max);
+        (min, max)
+    };
+
+    let terrain = create_heightmap(&rivers, &lakes, &TerrainConfig::default());
+    let (min, max) = ring(&terrain, 40.0);
+    // with diagonal steps as long as the others the ring would be a square, with the heights at
+    // its corners lower by a factor of `√2`, now it is an octagon with its heights up to 8% off
+    assert!(max / min < 1.15, "ring from {} to {}", min, max);
+
+    let mut slope = Map::new(size, size);
+    slope.map_coords(|x, _, _| if x < center { 0.5 } else { 1.0 });
+    let config = TerrainConfig {
+        slope: Some(slope),
+        ..TerrainConfig::default()
+    };
+    let flatter = create_heightmap(&rivers, &lakes, &config);
+    let ratio = (flatter[(center - 40, center)] - 20.0) / (terrain[(center - 40, center)] - 20.0);
+    assert!((ratio - 0.5).abs() < 0.02);
+    assert_eq!(
+        flatter[(center + 40, center)],
+        terrain[(center + 40, center)]
+    );
+}
+
+/// Check that the terrain follows the slope model, on a map with a river flowing into a lake
+#[test]
+fn test_slope_model_terrain() {
+    let size = 101;
+    let center = 50;
+    let mut rivers = Map::new(size, size);
+    for x in center + 1..size {
+        rivers[(x, center)] = 100.0;
+    }
+    let mut lakes = Map::new(size, size);
+    lakes[(center, center)] = 20.0;
+
+    let terrain = |model: Arc<dyn SlopeModel>| {
+        let config = TerrainConfig {
+            slope_model: model,
+            ..TerrainConfig::default()
+        };
+        create_heightmap(&rivers, &lakes, &config)
+    };
+    let inverse = terrain(Arc::new(InverseSlope::default()));
+    // a constant slope of `1`, half as steep as the default away from the river
+    let constant = terrain(Arc::new(PowerLawSlope { k: 1.0, m: 0.0 }));
+    let off_river = (center, center + 40);
+    let ratio = (constant[off_river] - 20.0) / (inverse[off_river] - 20.0);
+    assert!((ratio - 0.5).abs() < 1e-3, "ratio {}", ratio);
+
+    // the logistic model is steep away from the river and almost flat along it
+    let logistic = terrain(Arc::new(LogisticSlope::default()));
+    let along_river = (center + 40, center);
+    assert!(logistic[along_river] - 20.0 < 2.0);
+    assert!(logistic[off_river] - 20.0 > 20.0);
+    assert!(logistic[along_river] < inverse[along_river]);
+}
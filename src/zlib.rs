@@ -0,0 +1,146 @@
+use miniz_oxide::deflate::core::{create_comp_flags_from_zip_params, CompressorOxide};
+use miniz_oxide::deflate::stream::deflate;
+use miniz_oxide::inflate::stream::{inflate, InflateState};
+use miniz_oxide::{DataFormat, MZError, MZFlush, MZStatus};
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+/// Size of the buffers between the compressor and the underlying reader or writer
+const BUFFER_BYTES: usize = 1 << 16;
+
+/// Writer which compresses everything written to it as zlib stream into another writer
+pub struct ZlibWriter<W: Write> {
+    inner: W,
+    compressor: Box<CompressorOxide>,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> ZlibWriter<W> {
+    /// Compresses into `inner` with a `level` from `0` for no compression to `10` for the best
+    pub fn new(inner: W, level: u8) -> Self {
+        let flags = create_comp_flags_from_zip_params(level as i32, 15, 0);
+        Self {
+            inner,
+            compressor: Box::new(CompressorOxide::new(flags)),
+            buffer: vec![0; BUFFER_BYTES],
+        }
+    }
+
+    /// Ends the stream and returns the underlying writer
+    pub fn finish(mut self) -> Result<W> {
+        self.compress(&[], MZFlush::Finish)?;
+        Ok(self.inner)
+    }
+
+    fn compress(&mut self, mut input: &[u8], flush: MZFlush) -> Result<()> {
+        loop {
+            let result = deflate(&mut self.compressor, input, &mut self.buffer, flush);
+            input = &input[result.bytes_consumed..];
+            self.inner.write_all(&self.buffer[..result.bytes_written])?;
+            match result.status {
+                Ok(MZStatus::StreamEnd) => return Ok(()),
+                Ok(_) if flush == MZFlush::None && input.is_empty() => return Ok(()),
+                Ok(_) => {}
+                Err(err) => {
+                    return Err(Error::other(format!("zlib compression failed: {:?}", err)))
+                }
+            }
+        }
+    }
+}
+
+impl<W: Write> Write for ZlibWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if !buf.is_empty() {
+            self.compress(buf, MZFlush::None)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reader which decompresses a zlib stream from another reader
+pub struct ZlibReader<R: Read> {
+    inner: R,
+    state: Box<InflateState>,
+    buffer: Vec<u8>,
+    start: usize,
+    end: usize,
+    done: bool,
+}
+
+impl<R: Read> ZlibReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            state: InflateState::new_boxed(DataFormat::Zlib),
+            buffer: vec![0; BUFFER_BYTES],
+            start: 0,
+            end: 0,
+            done: false,
+        }
+    }
+}
+
+impl<R: Read> Read for ZlibReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> Result<usize> {
+        if out.is_empty() || self.done {
+            return Ok(0);
+        }
+        loop {
+            if self.start == self.end {
+                self.start = 0;
+                self.end = self.inner.read(&mut self.buffer)?;
+            }
+            // the stream has to end by itself before the input does
+            let eof = self.start == self.end;
+            let flush = if eof { MZFlush::Finish } else { MZFlush::None };
+
+            let input = &self.buffer[self.start..self.end];
+            let result = inflate(&mut self.state, input, out, flush);
+            self.start += result.bytes_consumed;
+            match result.status {
+                Ok(MZStatus::StreamEnd) => {
+                    self.done = true;
+                    return Ok(result.bytes_written);
+                }
+                Ok(_) if result.bytes_written > 0 => return Ok(result.bytes_written),
+                Ok(_) => {}
+                // all input was used up without any output, so more is needed
+                Err(MZError::Buf) if !eof && self.start == self.end => {}
+                Err(err) => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("corrupt zlib stream: {:?}", err),
+                    ))
+                }
+            }
+        }
+    }
+}
+
+/// Check that data of several chunks comes back the same, and that a cut off stream is rejected
+#[test]
+fn test_zlib_round_trip() {
+    let data: Vec<u8> = (0..300_000u32)
+        .map(|i| (i % 251) as u8 ^ (i / 4096) as u8)
+        .collect();
+
+    let mut zlib = ZlibWriter::new(Vec::new(), 1);
+    for chunk in data.chunks(1000) {
+        zlib.write_all(chunk).unwrap();
+    }
+    let compressed = zlib.finish().unwrap();
+    assert!(compressed.len() < data.len());
+
+    let mut loaded = Vec::new();
+    ZlibReader::new(&compressed[..])
+        .read_to_end(&mut loaded)
+        .unwrap();
+    assert!(loaded == data);
+
+    let cut = &compressed[..compressed.len() / 2];
+    assert!(ZlibReader::new(cut).read_to_end(&mut Vec::new()).is_err());
+}